@@ -0,0 +1,152 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// Trades per page, the most coinbase returns
+const PAGE_SIZE: usize = 1000;
+
+/// A trade from the public trades endpoint
+#[derive(Debug, Clone, Deserialize)]
+pub struct RestTrade {
+    pub time: DateTime<Utc>,
+    pub trade_id: u64,
+    #[serde(deserialize_with = "f64_from_string")]
+    pub price: f64,
+    #[serde(deserialize_with = "f64_from_string")]
+    pub size: f64,
+    /// the maker order's side, like on the matches channel
+    pub side: String,
+}
+
+fn f64_from_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
+
+/// A missed trade as published downstream, shaped like a `matches` message
+/// without the order ids and sequence the REST api doesn't return
+#[derive(Debug, Clone, Serialize)]
+pub struct BackfilledTrade<'a> {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub backfilled: bool,
+    pub product_id: &'a str,
+    pub trade_id: u64,
+    pub time: DateTime<Utc>,
+    pub price: f64,
+    pub size: f64,
+    pub side: &'a str,
+}
+
+impl<'a> BackfilledTrade<'a> {
+    pub fn new(product_id: &'a str, trade: &'a RestTrade) -> Self {
+        Self {
+            kind: "match",
+            backfilled: true,
+            product_id,
+            trade_id: trade.trade_id,
+            time: trade.time,
+            price: trade.price,
+            size: trade.size,
+            side: &trade.side,
+        }
+    }
+}
+
+/// Where to stop paging back through a product's trades
+#[derive(Debug, Clone, Copy)]
+pub enum Since {
+    /// trades after this trade id
+    TradeId(u64),
+    /// trades at or after this time
+    Time(DateTime<Utc>),
+}
+
+/// Pages through the public trades REST endpoint to recover trades the
+/// websocket missed
+pub struct Backfill {
+    client: reqwest::Client,
+    uri: String,
+    max_pages: usize,
+}
+
+impl Backfill {
+    pub fn new(uri: &str, max_pages: usize) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            uri: uri.trim_end_matches('/').to_owned(),
+            max_pages,
+        }
+    }
+
+    /// Trades of `product_id` from `since` up to (not including) trade
+    /// `before`, or up to now without it, oldest first. Stops after
+    /// `max_pages` pages, the oldest trades are lost then.
+    pub async fn trades(
+        &self,
+        product_id: &str,
+        since: Since,
+        before: Option<u64>,
+    ) -> Result<Vec<RestTrade>, String> {
+        let mut trades = Vec::new();
+        // coinbase pages from the newest trade back, `after` is the cursor
+        // returning trades older than the given trade id
+        let mut cursor = before;
+
+        for page in 0.. {
+            if page == self.max_pages {
+                println!(
+                    "{}: backfill stopped after {} pages, older trades are lost",
+                    product_id, self.max_pages
+                );
+                break;
+            }
+
+            let batch = self.page(product_id, cursor).await?;
+            let oldest = match batch.last() {
+                Some(trade) => trade.trade_id,
+                None => break,
+            };
+
+            let mut done = false;
+            for trade in batch {
+                let wanted = match since {
+                    Since::TradeId(trade_id) => trade.trade_id > trade_id,
+                    Since::Time(time) => trade.time >= time,
+                };
+                if wanted {
+                    trades.push(trade);
+                } else {
+                    done = true;
+                }
+            }
+            if done || oldest <= 1 {
+                break;
+            }
+            cursor = Some(oldest);
+        }
+
+        trades.reverse();
+        Ok(trades)
+    }
+
+    async fn page(&self, product_id: &str, after: Option<u64>) -> Result<Vec<RestTrade>, String> {
+        let url = format!("{}/products/{}/trades", self.uri, product_id);
+        let mut query = vec![("limit", PAGE_SIZE.to_string())];
+        if let Some(after) = after {
+            query.push(("after", after.to_string()));
+        }
+
+        self.client
+            .get(url)
+            // coinbase rejects requests without a user agent
+            .header("User-Agent", "coinbase-kafka-producer")
+            .query(&query)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
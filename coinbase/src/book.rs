@@ -0,0 +1,173 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Utc};
+use coinbase_pro_rs::structs::reqs::OrderSide;
+use coinbase_pro_rs::structs::wsfeed::{Level2, Level2SnapshotRecord};
+use serde::Serialize;
+
+/// A price usable as a map key, coinbase never sends NaN prices
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Price(pub f64);
+
+impl Eq for Price {}
+
+impl PartialOrd for Price {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Price {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// An aggregated (level 2) order book: the total size resting at each price
+#[derive(Debug, Default)]
+pub struct OrderBook {
+    bids: BTreeMap<Price, f64>,
+    asks: BTreeMap<Price, f64>,
+}
+
+impl OrderBook {
+    /// Replaces the whole book with a snapshot
+    pub fn reset(&mut self, bids: &[Level2SnapshotRecord], asks: &[Level2SnapshotRecord]) {
+        self.bids = bids.iter().map(|r| (Price(r.price), r.size)).collect();
+        self.asks = asks.iter().map(|r| (Price(r.price), r.size)).collect();
+    }
+
+    /// Sets the size at a price level, a size of zero removes the level
+    pub fn update(&mut self, side: &OrderSide, price: f64, size: f64) {
+        let levels = match side {
+            OrderSide::Buy => &mut self.bids,
+            OrderSide::Sell => &mut self.asks,
+        };
+
+        if size == 0.0 {
+            levels.remove(&Price(price));
+        } else {
+            levels.insert(Price(price), size);
+        }
+    }
+
+    /// Best bids, highest first
+    pub fn top_bids(&self, depth: usize) -> Vec<[f64; 2]> {
+        self.bids.iter().rev().take(depth).map(|(p, s)| [p.0, *s]).collect()
+    }
+
+    /// Best asks, lowest first
+    pub fn top_asks(&self, depth: usize) -> Vec<[f64; 2]> {
+        self.asks.iter().take(depth).map(|(p, s)| [p.0, *s]).collect()
+    }
+
+    pub fn best_bid(&self) -> Option<f64> {
+        self.bids.keys().next_back().map(|p| p.0)
+    }
+
+    pub fn best_ask(&self) -> Option<f64> {
+        self.asks.keys().next().map(|p| p.0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bids.is_empty() && self.asks.is_empty()
+    }
+
+    /// The top `depth` levels of each side with derived metrics
+    pub fn snapshot(&self, product_id: &str, depth: usize, time: DateTime<Utc>) -> BookSnapshot {
+        let bids = self.top_bids(depth);
+        let asks = self.top_asks(depth);
+
+        let (spread, mid) = match (self.best_bid(), self.best_ask()) {
+            (Some(bid), Some(ask)) => (Some(ask - bid), Some((ask + bid) / 2.0)),
+            _ => (None, None),
+        };
+
+        // -1 when all the size is on the ask side, 1 when it's all bids
+        let bid_size: f64 = bids.iter().map(|[_, size]| size).sum();
+        let ask_size: f64 = asks.iter().map(|[_, size]| size).sum();
+        let imbalance = if bid_size + ask_size > 0.0 {
+            Some((bid_size - ask_size) / (bid_size + ask_size))
+        } else {
+            None
+        };
+
+        BookSnapshot {
+            kind: "book",
+            product_id: product_id.to_owned(),
+            time,
+            bids,
+            asks,
+            spread,
+            mid,
+            imbalance,
+        }
+    }
+}
+
+/// The top of a book as published downstream, levels are `[price, size]`
+#[derive(Debug, Clone, Serialize)]
+pub struct BookSnapshot {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub product_id: String,
+    pub time: DateTime<Utc>,
+    pub bids: Vec<[f64; 2]>,
+    pub asks: Vec<[f64; 2]>,
+    pub spread: Option<f64>,
+    pub mid: Option<f64>,
+    /// (bid size - ask size) / (bid size + ask size) over the published levels
+    pub imbalance: Option<f64>,
+}
+
+/// The order books of every product, built from the `level2` channel
+#[derive(Debug, Default)]
+pub struct OrderBooks {
+    books: HashMap<String, OrderBook>,
+}
+
+impl OrderBooks {
+    /// Applies a `level2` snapshot or update
+    pub fn apply(&mut self, level2: &Level2) {
+        match level2 {
+            Level2::Snapshot {
+                product_id,
+                bids,
+                asks,
+                ..
+            } => {
+                self.books
+                    .entry(product_id.clone())
+                    .or_default()
+                    .reset(bids, asks);
+            }
+            Level2::L2update {
+                product_id,
+                changes,
+                ..
+            } => {
+                // updates before the first snapshot can't be placed in a book
+                if let Some(book) = self.books.get_mut(product_id) {
+                    for change in changes {
+                        book.update(&change.side, change.price, change.size);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Drops the book of a product that was unsubscribed
+    pub fn remove(&mut self, product_id: &str) {
+        self.books.remove(product_id);
+    }
+
+    /// Snapshots of every book that has levels
+    pub fn snapshots(&self, depth: usize, time: DateTime<Utc>) -> Vec<BookSnapshot> {
+        self.books
+            .iter()
+            .filter(|(_, book)| !book.is_empty())
+            .map(|(product_id, book)| book.snapshot(product_id, depth, time))
+            .collect()
+    }
+}
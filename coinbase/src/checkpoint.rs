@@ -0,0 +1,60 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// The last message processed per product, written periodically and on
+/// shutdown so the next run knows where the feed left off
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// when the checkpoint was written
+    pub time: Option<DateTime<Utc>>,
+    pub products: BTreeMap<String, ProductCheckpoint>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ProductCheckpoint {
+    /// last exchange sequence processed
+    pub sequence: Option<u64>,
+    /// last trade processed
+    pub trade_id: Option<u64>,
+}
+
+impl Checkpoint {
+    /// Reads a checkpoint, an empty one if the file doesn't exist yet
+    pub fn load(path: &Path) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(data) => serde_json::from_str(&data)
+                .map_err(|e| format!("invalid {}: {}", path.display(), e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("failed reading {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Records a processed message, older sequences and trades don't move
+    /// the checkpoint back
+    pub fn observe(&mut self, product_id: &str, sequence: Option<u64>, trade_id: Option<u64>) {
+        if sequence.is_none() && trade_id.is_none() {
+            return;
+        }
+
+        let product = self.products.entry(product_id.to_owned()).or_default();
+        product.sequence = product.sequence.max(sequence);
+        product.trade_id = product.trade_id.max(trade_id);
+    }
+
+    /// Writes the checkpoint next to `path` and moves it in place, so a crash
+    /// while writing leaves the previous one intact
+    pub fn save(&mut self, path: &Path) -> Result<(), String> {
+        self.time = Some(Utc::now());
+
+        let data = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)
+            .and_then(|_| fs::rename(&tmp, path))
+            .map_err(|e| format!("failed writing {}: {}", path.display(), e))
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Command};
+use serde_json::Value;
+
+/// Prefix of the environment variables overriding options, e.g.
+/// COINBASE_BROKER for --broker
+const ENV_PREFIX: &str = "COINBASE_";
+
+/// Options read from a TOML or YAML file, keyed by long flag name
+pub type ConfigFile = BTreeMap<String, Value>;
+
+/// Reads a config file, picking the format from its extension. Keys are the
+/// long flag names, nested tables join their keys with '-' so `[kafka]
+/// ca-file = ...` sets --kafka-ca-file.
+pub fn load(path: &Path) -> Result<ConfigFile, String> {
+    let data = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed reading {}: {}", path.display(), e))?;
+    let invalid = |e: String| format!("invalid {}: {}", path.display(), e);
+
+    let value: Value = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => from_toml(toml::from_str(&data).map_err(|e| invalid(e.to_string()))?),
+        Some("yaml") | Some("yml") => {
+            serde_yaml::from_str(&data).map_err(|e| invalid(e.to_string()))?
+        }
+        _ => {
+            return Err(format!(
+                "unknown config format {}, expected .toml, .yaml or .yml",
+                path.display()
+            ))
+        }
+    };
+
+    let mut options = ConfigFile::new();
+    match value {
+        Value::Object(table) => flatten("", table, &mut options),
+        Value::Null => {}
+        _ => return Err(invalid("expected a table of options".to_string())),
+    }
+    Ok(options)
+}
+
+/// Toml dates are kept as the text they were written as
+fn from_toml(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(value) => Value::String(value),
+        toml::Value::Integer(value) => Value::from(value),
+        toml::Value::Float(value) => Value::from(value),
+        toml::Value::Boolean(value) => Value::Bool(value),
+        toml::Value::Datetime(value) => Value::String(value.to_string()),
+        toml::Value::Array(values) => Value::Array(values.into_iter().map(from_toml).collect()),
+        toml::Value::Table(table) => Value::Object(
+            table
+                .into_iter()
+                .map(|(key, value)| (key, from_toml(value)))
+                .collect(),
+        ),
+    }
+}
+
+fn flatten(prefix: &str, table: serde_json::Map<String, Value>, options: &mut ConfigFile) {
+    for (key, value) in table {
+        let key = format!("{}{}", prefix, key.replace('_', "-"));
+        match value {
+            Value::Object(table) => flatten(&format!("{}-", key), table, options),
+            value => {
+                options.insert(key, value);
+            }
+        }
+    }
+}
+
+/// The arguments the environment and the config file add to the command
+/// line. Options on the command line win over COINBASE_* variables, which
+/// win over the file. Unknown keys and values of the wrong shape are errors,
+/// values themselves are checked by clap like any other argument.
+pub fn layer(
+    command: &Command,
+    cli: &ArgMatches,
+    file: &ConfigFile,
+) -> Result<Vec<String>, String> {
+    let mut known = Vec::new();
+    let mut args = Vec::new();
+
+    for arg in command.get_arguments() {
+        let long = match arg.get_long() {
+            Some(long) if !matches!(long, "config" | "help" | "version") => long,
+            _ => continue,
+        };
+        known.push(long);
+
+        if cli.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+
+        let takes_value = arg.get_action().takes_values();
+        let env = format!("{}{}", ENV_PREFIX, long.to_uppercase().replace('-', "_"));
+        if let Ok(value) = std::env::var(&env) {
+            let value = match (takes_value, value.as_str()) {
+                (true, _) => Value::String(value),
+                (false, "true" | "1") => Value::Bool(true),
+                (false, "false" | "0" | "") => Value::Bool(false),
+                (false, _) => return Err(format!("{} must be true or false", env)),
+            };
+            push(&mut args, long, takes_value, &value).map_err(|e| format!("{}: {}", env, e))?;
+        } else if let Some(value) = file.get(long) {
+            push(&mut args, long, takes_value, value).map_err(|e| format!("{}: {}", long, e))?;
+        }
+    }
+
+    let unknown: Vec<&str> = file
+        .keys()
+        .map(String::as_str)
+        .filter(|key| !known.contains(key))
+        .collect();
+    if !unknown.is_empty() {
+        return Err(format!("unknown options in the config file: {}", unknown.join(", ")));
+    }
+
+    Ok(args)
+}
+
+/// Adds `--<long>` for a flag, or `--<long>=<value>` for every value of an option
+fn push(
+    args: &mut Vec<String>,
+    long: &str,
+    takes_value: bool,
+    value: &Value,
+) -> Result<(), String> {
+    if !takes_value {
+        return match value {
+            Value::Bool(true) => {
+                args.push(format!("--{}", long));
+                Ok(())
+            }
+            Value::Bool(false) => Ok(()),
+            _ => Err("expected true or false".to_string()),
+        };
+    }
+
+    match value {
+        Value::Null => {}
+        Value::String(value) => args.push(format!("--{}={}", long, value)),
+        Value::Number(value) => args.push(format!("--{}={}", long, value)),
+        Value::Bool(value) => args.push(format!("--{}={}", long, value)),
+        Value::Array(values) => {
+            for value in values {
+                if matches!(value, Value::Array(_) | Value::Object(_)) {
+                    return Err("expected a list of values".to_string());
+                }
+                push(args, long, takes_value, value)?;
+            }
+        }
+        Value::Object(_) => return Err("expected a value".to_string()),
+    }
+    Ok(())
+}
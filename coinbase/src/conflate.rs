@@ -0,0 +1,141 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+
+/// The latest ticker of a product and what traded since the last emit
+struct Pending {
+    ticker: Value,
+    sequence: Option<u64>,
+    time: Option<DateTime<Utc>>,
+    volume: f64,
+    trades: u64,
+}
+
+/// A conflated ticker ready to publish
+pub struct Conflated {
+    pub product_id: String,
+    pub sequence: Option<u64>,
+    pub time: Option<DateTime<Utc>>,
+    pub payload: String,
+}
+
+/// Keeps the latest ticker of every product between emits. The emitted
+/// ticker's `last_size` is the size traded since the previous one, so the
+/// volume adds up the same as on the full rate stream, and `trades` counts
+/// the tickers it stands for.
+#[derive(Default)]
+pub struct Conflation {
+    pending: BTreeMap<String, Pending>,
+}
+
+impl Conflation {
+    pub fn update(
+        &mut self,
+        product_id: &str,
+        sequence: Option<u64>,
+        time: Option<DateTime<Utc>>,
+        data: &str,
+    ) {
+        let ticker: Value = match serde_json::from_str(data) {
+            Ok(ticker @ Value::Object(_)) => ticker,
+            _ => return,
+        };
+        // the size is a string on the wire, a number once typed
+        let size = match &ticker["last_size"] {
+            Value::Number(size) => size.as_f64(),
+            Value::String(size) => size.parse().ok(),
+            _ => None,
+        };
+
+        let pending = self.pending(product_id);
+        if let Some(size) = size {
+            pending.volume += size;
+            pending.trades += 1;
+        }
+        pending.ticker = ticker;
+        pending.sequence = sequence;
+        pending.time = time;
+    }
+
+    /// Counts a trade recovered by a backfill. It's older than the live
+    /// ticker that triggered the backfill, so it only adds to the volume.
+    pub fn backfilled(&mut self, product_id: &str, size: f64) {
+        let pending = self.pending(product_id);
+        pending.volume += size;
+        pending.trades += 1;
+    }
+
+    /// One ticker per product updated since the last drain
+    pub fn drain(&mut self) -> Vec<Conflated> {
+        let mut conflated = Vec::new();
+        for (product_id, mut pending) in std::mem::take(&mut self.pending) {
+            // backfilled trades wait for a ticker to go out with
+            if pending.ticker.is_null() {
+                self.pending.insert(product_id, pending);
+                continue;
+            }
+
+            if pending.trades > 0 {
+                pending.ticker["last_size"] = Value::from(pending.volume);
+            }
+            pending.ticker["trades"] = Value::from(pending.trades);
+            conflated.push(Conflated {
+                product_id,
+                sequence: pending.sequence,
+                time: pending.time,
+                payload: pending.ticker.to_string(),
+            });
+        }
+        conflated
+    }
+
+    fn pending(&mut self, product_id: &str) -> &mut Pending {
+        self.pending
+            .entry(product_id.to_owned())
+            .or_insert_with(|| Pending {
+                ticker: Value::Null,
+                sequence: None,
+                time: None,
+                volume: 0.0,
+                trades: 0,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ticker(trade_id: u64, size: &str) -> String {
+        serde_json::json!({
+            "type": "ticker",
+            "product_id": "BTC-USD",
+            "trade_id": trade_id,
+            "last_size": size,
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn sums_the_volume_between_emits() {
+        let mut conflation = Conflation::default();
+        conflation.backfilled("BTC-USD", 0.5);
+        assert!(conflation.drain().is_empty(), "nothing to emit without a ticker");
+
+        conflation.backfilled("BTC-USD", 0.25);
+        conflation.update("BTC-USD", Some(11), None, &ticker(11, "0.1"));
+        conflation.update("BTC-USD", Some(12), None, &ticker(12, "0.2"));
+
+        let drained = conflation.drain();
+        assert_eq!(drained.len(), 1);
+        let emitted: Value = serde_json::from_str(&drained[0].payload).unwrap();
+        assert_eq!(emitted["trade_id"], 12);
+        assert_eq!(emitted["trades"], 4);
+        let volume = emitted["last_size"].as_f64().unwrap();
+        assert!((volume - 1.05).abs() < 1e-9, "{}", volume);
+        assert_eq!(drained[0].sequence, Some(12));
+
+        assert!(conflation.drain().is_empty());
+    }
+}
@@ -0,0 +1,53 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+
+use crate::feed::Channel;
+
+/// The last `window` trade ids of one stream, oldest first
+struct Recent<T> {
+    order: VecDeque<T>,
+    ids: HashSet<T>,
+}
+
+/// Remembers the trade ids recently published on each channel of each
+/// product, so a trade delivered again after a reconnect or by a backfill is
+/// only published once. Old ids are forgotten beyond `window` per stream.
+pub struct TradeIds<T> {
+    window: usize,
+    streams: HashMap<(Channel, String), Recent<T>>,
+}
+
+impl<T: Clone + Eq + Hash> TradeIds<T> {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Records a trade, false when it was already seen on this channel
+    pub fn first_seen(&mut self, channel: Channel, product_id: &str, trade_id: &T) -> bool {
+        if self.window == 0 {
+            return true;
+        }
+
+        let recent = self
+            .streams
+            .entry((channel, product_id.to_owned()))
+            .or_insert_with(|| Recent {
+                order: VecDeque::new(),
+                ids: HashSet::new(),
+            });
+        if !recent.ids.insert(trade_id.clone()) {
+            return false;
+        }
+
+        recent.order.push_back(trade_id.clone());
+        if recent.order.len() > self.window {
+            if let Some(oldest) = recent.order.pop_front() {
+                recent.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use coinbase_pro_rs::structs::public::ProductStatus;
+use coinbase_pro_rs::{ASync, Public};
+use tokio::sync::watch;
+
+use crate::feed::Status;
+use crate::metrics;
+use crate::shutdown;
+
+/// How long to wait before retrying when the first product listing fails
+const FIRST_LISTING_RETRY: Duration = Duration::from_secs(5);
+
+/// Which markets to subscribe to besides the ones passed by hand
+#[derive(Debug, Clone, Default)]
+pub struct ProductFilter {
+    /// quote currency e.g. 'USD'
+    pub quote: Option<String>,
+    /// glob over the product id, '*' matches any run of characters and '?'
+    /// a single one e.g. '*-USDC'
+    pub pattern: Option<String>,
+}
+
+impl ProductFilter {
+    pub fn is_empty(&self) -> bool {
+        self.quote.is_none() && self.pattern.is_none()
+    }
+
+    /// Whether a product passes every part of the filter
+    pub fn matches(&self, product_id: &str, quote_currency: &str) -> bool {
+        let quote = match &self.quote {
+            Some(quote) => quote.eq_ignore_ascii_case(quote_currency),
+            None => true,
+        };
+        let pattern = match &self.pattern {
+            Some(pattern) => glob_match(
+                pattern.to_uppercase().as_bytes(),
+                product_id.to_uppercase().as_bytes(),
+            ),
+            None => true,
+        };
+        quote && pattern
+    }
+}
+
+impl std::fmt::Display for ProductFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(quote) = &self.quote {
+            parts.push(format!("quote {}", quote));
+        }
+        if let Some(pattern) = &self.pattern {
+            parts.push(format!("match '{}'", pattern));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], text) || (!text.is_empty() && glob_match(pattern, &text[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &text[1..]),
+        (Some(p), Some(t)) if p == t => glob_match(&pattern[1..], &text[1..]),
+        _ => false,
+    }
+}
+
+/// Keeps the list of subscribed markets up to date.
+///
+/// The markets passed by hand are always subscribed. The ones matching the
+/// filter come from the REST products listing, which is refreshed
+/// periodically, and from `status` channel messages, which take delisted or
+/// disabled markets out right away. Every change is published on a watch
+/// channel the feed resubscribes from.
+pub struct Discovery {
+    client: Public<ASync>,
+    filter: ProductFilter,
+    pinned: Mutex<Vec<String>>,
+    tx: watch::Sender<Vec<String>>,
+}
+
+impl Discovery {
+    pub fn new(uri: &str, filter: ProductFilter, pinned: Vec<String>) -> Self {
+        let (tx, _) = watch::channel(pinned.clone());
+        Self {
+            client: Public::new(uri),
+            filter,
+            pinned: Mutex::new(pinned),
+            tx,
+        }
+    }
+
+    /// The subscribed markets, updated on every change
+    pub fn subscribe(&self) -> watch::Receiver<Vec<String>> {
+        self.tx.subscribe()
+    }
+
+    /// Lists the matching markets now and then every `refresh` until
+    /// `shutdown` is set
+    pub async fn run(&self, refresh: Duration, mut shutdown: watch::Receiver<bool>) {
+        let mut listed = false;
+        loop {
+            let listing = tokio::select! {
+                listing = self.list() => listing,
+                _ = shutdown::stopped(&mut shutdown) => return,
+            };
+            match listing {
+                Ok(discovered) => {
+                    listed = true;
+                    self.replace(discovered);
+                }
+                Err(e) => println!("Failed listing products: {}", e),
+            }
+
+            let delay = if listed { refresh } else { FIRST_LISTING_RETRY };
+            if !shutdown::sleep(delay, &mut shutdown).await {
+                return;
+            }
+        }
+    }
+
+    /// Replaces the markets passed by hand. Dropped ones the filter matches
+    /// come back with the next listing.
+    pub fn set_pinned(&self, pinned: Vec<String>) {
+        let old = std::mem::replace(&mut *self.pinned.lock().unwrap(), pinned.clone());
+
+        let mut products: BTreeSet<String> = self.tx.borrow().iter().cloned().collect();
+        for product in old.iter().filter(|product| !pinned.contains(product)) {
+            products.remove(product);
+        }
+        products.extend(pinned);
+        self.publish(products);
+    }
+
+    /// Adds markets that came online and drops the ones that went offline
+    pub fn apply_status(&self, status: &Status) {
+        let pinned = self.pinned.lock().unwrap().clone();
+        let mut products: BTreeSet<String> = self.tx.borrow().iter().cloned().collect();
+
+        for product in &status.products {
+            let quote = product
+                .extra
+                .get("quote_currency")
+                .and_then(|quote| quote.as_str())
+                .unwrap_or_default();
+            if pinned.contains(&product.id) || !self.filter.matches(&product.id, quote) {
+                continue;
+            }
+
+            if product.status == "online" {
+                if products.insert(product.id.clone()) {
+                    println!("{}: now online, subscribing", product.id);
+                }
+            } else if products.remove(&product.id) {
+                println!("{}: now {}, unsubscribing", product.id, product.status);
+            }
+        }
+
+        self.publish(products);
+    }
+
+    async fn list(&self) -> Result<Vec<String>, String> {
+        let products = self.client.get_products().await.map_err(|e| e.to_string())?;
+        Ok(products
+            .into_iter()
+            .filter(|p| matches!(p.status, ProductStatus::Online))
+            .filter(|p| self.filter.matches(&p.id, &p.quote_currency))
+            .map(|p| p.id)
+            .collect())
+    }
+
+    fn replace(&self, discovered: Vec<String>) {
+        let mut products: BTreeSet<String> = self.pinned.lock().unwrap().iter().cloned().collect();
+        products.extend(discovered);
+        self.publish(products);
+    }
+
+    fn publish(&self, products: BTreeSet<String>) {
+        let products: Vec<String> = products.into_iter().collect();
+        metrics::SUBSCRIBED_PRODUCTS.set(products.len() as i64);
+
+        self.tx.send_if_modified(|current| {
+            if *current == products {
+                return false;
+            }
+            println!("subscribed markets: {}", products.join(","));
+            *current = products;
+            true
+        });
+    }
+}
@@ -0,0 +1,44 @@
+use chrono::{DateTime, Utc};
+use feed_schema::{FeedRecord, Format, Metadata, Payload, SCHEMA_VERSION};
+
+lazy_static! {
+    /// The host the producer runs on, from `$HOSTNAME` or `/etc/hostname`
+    static ref HOSTNAME: String = std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+}
+
+/// Ingest metadata sent along with every kafka record
+pub fn metadata(
+    product: &str,
+    channel: &str,
+    sequence: Option<u64>,
+    exchange_time: Option<DateTime<Utc>>,
+    received: DateTime<Utc>,
+) -> Metadata {
+    Metadata {
+        product: product.to_owned(),
+        channel: channel.to_owned(),
+        sequence,
+        exchange_time,
+        received,
+        producer: HOSTNAME.clone(),
+        schema_version: SCHEMA_VERSION,
+    }
+}
+
+/// Encodes a json message and its metadata as `format`.
+///
+/// The kafka client doesn't support record headers, so the metadata travels
+/// in the value. With json the message is embedded as is, the binary formats
+/// type tickers and carry everything else as json.
+pub fn encode(format: Format, meta: Metadata, payload: &str) -> Result<Vec<u8>, String> {
+    let payload = match format {
+        Format::Json => Payload::Json(payload.to_owned()),
+        _ => Payload::from_json(&meta.channel, payload),
+    };
+    feed_schema::encode(format, &FeedRecord { meta, payload })
+}
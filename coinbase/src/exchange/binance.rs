@@ -0,0 +1,93 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use feed_schema::{Normalized, Quote, Side, Trade};
+use serde_json::Value;
+
+use super::{decimal, ExchangeAdapter};
+
+pub const BINANCE_URL: &str = "wss://stream.binance.com:9443/ws";
+
+/// Binance trades from the `<symbol>@trade` streams and quotes from
+/// `<symbol>@bookTicker`
+pub struct Binance {
+    url: String,
+}
+
+impl Binance {
+    pub fn new(url: &str) -> Self {
+        Self { url: url.to_owned() }
+    }
+}
+
+impl ExchangeAdapter for Binance {
+    fn name(&self) -> &'static str {
+        "binance"
+    }
+
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn subscriptions(&self, symbols: &[String]) -> Vec<String> {
+        vec![request("SUBSCRIBE", symbols, 1)]
+    }
+
+    fn unsubscriptions(&self, symbols: &[String]) -> Vec<String> {
+        vec![request("UNSUBSCRIBE", symbols, 2)]
+    }
+
+    fn parse(&self, frame: &str, received: DateTime<Utc>) -> Result<Vec<Normalized>, String> {
+        let message: Value = serde_json::from_str(frame).map_err(|e| e.to_string())?;
+        let symbol = message["s"].as_str().unwrap_or_default().to_owned();
+
+        let normalized = if message["e"] == "trade" {
+            let millis = message["T"].as_i64().ok_or("missing trade time")?;
+            Normalized::Trade(Trade {
+                exchange: self.name().to_owned(),
+                symbol,
+                price: decimal(&message["p"], "price")?,
+                size: decimal(&message["q"], "quantity")?,
+                // `m` is set when the buyer is the maker, so the seller took
+                side: if message["m"].as_bool().unwrap_or(false) { Side::Sell } else { Side::Buy },
+                ts: NaiveDateTime::from_timestamp_millis(millis)
+                    .map(|time| time.and_utc())
+                    .ok_or_else(|| format!("invalid trade time: {}", millis))?,
+                id: Some(message["t"].to_string()),
+            })
+        } else if message["u"].is_u64() && message["b"].is_string() {
+            // book tickers have neither an event type nor a time
+            Normalized::Quote(Quote {
+                exchange: self.name().to_owned(),
+                symbol,
+                bid: decimal(&message["b"], "bid")?,
+                bid_size: Some(decimal(&message["B"], "bid size")?),
+                ask: decimal(&message["a"], "ask")?,
+                ask_size: Some(decimal(&message["A"], "ask size")?),
+                ts: received,
+            })
+        } else {
+            // subscription results
+            return Ok(Vec::new());
+        };
+
+        Ok(vec![normalized])
+    }
+}
+
+/// A request on the trade and book ticker streams of `symbols`
+fn request(method: &str, symbols: &[String], id: u64) -> String {
+    // stream names are lower case
+    let streams: Vec<String> = symbols
+        .iter()
+        .flat_map(|symbol| {
+            let symbol = symbol.to_ascii_lowercase();
+            [format!("{}@trade", symbol), format!("{}@bookTicker", symbol)]
+        })
+        .collect();
+
+    serde_json::json!({
+        "method": method,
+        "params": streams,
+        "id": id,
+    })
+    .to_string()
+}
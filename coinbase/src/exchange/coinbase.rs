@@ -0,0 +1,85 @@
+use chrono::{DateTime, Utc};
+use feed_schema::{Normalized, Quote, Side, Trade};
+use serde_json::Value;
+
+use super::{decimal, ExchangeAdapter};
+
+/// Coinbase trades from the `matches` channel and quotes from `ticker`
+pub struct Coinbase {
+    url: String,
+}
+
+impl Coinbase {
+    pub fn new(url: &str) -> Self {
+        Self { url: url.to_owned() }
+    }
+}
+
+impl ExchangeAdapter for Coinbase {
+    fn name(&self) -> &'static str {
+        "coinbase"
+    }
+
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn subscriptions(&self, symbols: &[String]) -> Vec<String> {
+        vec![subscription("subscribe", symbols)]
+    }
+
+    fn unsubscriptions(&self, symbols: &[String]) -> Vec<String> {
+        vec![subscription("unsubscribe", symbols)]
+    }
+
+    fn parse(&self, frame: &str, _received: DateTime<Utc>) -> Result<Vec<Normalized>, String> {
+        let message: Value = serde_json::from_str(frame).map_err(|e| e.to_string())?;
+        let symbol = || message["product_id"].as_str().unwrap_or_default().to_owned();
+        let time = || -> Result<DateTime<Utc>, String> {
+            message["time"]
+                .as_str()
+                .and_then(|time| time.parse().ok())
+                .ok_or_else(|| format!("invalid time: {}", message["time"]))
+        };
+
+        let normalized = match message["type"].as_str() {
+            // `last_match` repeats a trade on every subscribe, skip it
+            Some("match") => Normalized::Trade(Trade {
+                exchange: self.name().to_owned(),
+                symbol: symbol(),
+                price: decimal(&message["price"], "price")?,
+                size: decimal(&message["size"], "size")?,
+                // coinbase sends the maker's side
+                side: match message["side"].as_str() {
+                    Some("buy") => Side::Sell,
+                    Some("sell") => Side::Buy,
+                    _ => return Err(format!("invalid side: {}", message["side"])),
+                },
+                ts: time()?,
+                id: Some(message["trade_id"].to_string()),
+            }),
+            // the ticker sent right after subscribing has no time
+            Some("ticker") if !message["time"].is_null() => Normalized::Quote(Quote {
+                exchange: self.name().to_owned(),
+                symbol: symbol(),
+                bid: decimal(&message["best_bid"], "best_bid")?,
+                bid_size: decimal(&message["best_bid_size"], "best_bid_size").ok(),
+                ask: decimal(&message["best_ask"], "best_ask")?,
+                ask_size: decimal(&message["best_ask_size"], "best_ask_size").ok(),
+                ts: time()?,
+            }),
+            _ => return Ok(Vec::new()),
+        };
+
+        Ok(vec![normalized])
+    }
+}
+
+fn subscription(kind: &str, symbols: &[String]) -> String {
+    serde_json::json!({
+        "type": kind,
+        "product_ids": symbols,
+        "channels": ["matches", "ticker"],
+    })
+    .to_string()
+}
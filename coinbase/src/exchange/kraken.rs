@@ -0,0 +1,99 @@
+use chrono::{DateTime, Utc};
+use feed_schema::{Normalized, Quote, Side, Trade};
+use serde_json::Value;
+
+use super::{decimal, unix_seconds, ExchangeAdapter};
+
+pub const KRAKEN_URL: &str = "wss://ws.kraken.com";
+
+/// Kraken trades from the `trade` channel and quotes from `spread`
+pub struct Kraken {
+    url: String,
+}
+
+impl Kraken {
+    pub fn new(url: &str) -> Self {
+        Self { url: url.to_owned() }
+    }
+}
+
+impl ExchangeAdapter for Kraken {
+    fn name(&self) -> &'static str {
+        "kraken"
+    }
+
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn subscriptions(&self, symbols: &[String]) -> Vec<String> {
+        events("subscribe", symbols)
+    }
+
+    fn unsubscriptions(&self, symbols: &[String]) -> Vec<String> {
+        events("unsubscribe", symbols)
+    }
+
+    fn parse(&self, frame: &str, _received: DateTime<Utc>) -> Result<Vec<Normalized>, String> {
+        let message: Value = serde_json::from_str(frame).map_err(|e| e.to_string())?;
+
+        // channel data comes as `[channel id, data, channel name, pair]`,
+        // events like heartbeats and subscription acks as objects
+        let (data, name, pair) = match message.as_array().map(Vec::as_slice) {
+            Some([_, data, Value::String(name), Value::String(pair)]) => (data, name, pair),
+            _ => return Ok(Vec::new()),
+        };
+
+        match name.as_str() {
+            // [[price, volume, time, side, order type, misc], ...]
+            "trade" => {
+                let trades = data.as_array().ok_or("trades aren't an array")?;
+                trades
+                    .iter()
+                    .map(|trade| {
+                        Ok(Normalized::Trade(Trade {
+                            exchange: self.name().to_owned(),
+                            symbol: pair.clone(),
+                            price: decimal(&trade[0], "price")?,
+                            size: decimal(&trade[1], "volume")?,
+                            side: match trade[3].as_str() {
+                                Some("b") => Side::Buy,
+                                Some("s") => Side::Sell,
+                                _ => return Err(format!("invalid side: {}", trade[3])),
+                            },
+                            ts: unix_seconds(&trade[2], "time")?,
+                            // kraken has no trade ids
+                            id: None,
+                        }))
+                    })
+                    .collect()
+            }
+            // [bid, ask, time, bid volume, ask volume]
+            "spread" => Ok(vec![Normalized::Quote(Quote {
+                exchange: self.name().to_owned(),
+                symbol: pair.clone(),
+                bid: decimal(&data[0], "bid")?,
+                bid_size: decimal(&data[3], "bid volume").ok(),
+                ask: decimal(&data[1], "ask")?,
+                ask_size: decimal(&data[4], "ask volume").ok(),
+                ts: unix_seconds(&data[2], "time")?,
+            })]),
+            _ => Ok(Vec::new()),
+        }
+    }
+}
+
+/// One event per channel, kraken takes a single subscription name each
+fn events(event: &str, symbols: &[String]) -> Vec<String> {
+    ["trade", "spread"]
+        .iter()
+        .map(|name| {
+            serde_json::json!({
+                "event": event,
+                "pair": symbols,
+                "subscription": { "name": name },
+            })
+            .to_string()
+        })
+        .collect()
+}
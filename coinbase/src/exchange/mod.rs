@@ -0,0 +1,177 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use feed_schema::Normalized;
+use futures::future::{self, BoxFuture};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::watch;
+
+use crate::feed::{Backoff, FeedEvent, Frame};
+use crate::websocket::{self, Flow, Session};
+
+mod binance;
+mod coinbase;
+mod kraken;
+
+pub use binance::{Binance, BINANCE_URL};
+pub use coinbase::Coinbase;
+pub use kraken::{Kraken, KRAKEN_URL};
+
+/// Speaks one exchange's public websocket protocol and normalizes its trades
+/// and quotes. Adapters don't do any io themselves, so they can be fed
+/// recorded frames.
+pub trait ExchangeAdapter: Send + Sync + 'static {
+    /// the exchange name used in records and topics
+    fn name(&self) -> &'static str;
+
+    /// the websocket to connect to
+    fn url(&self) -> &str;
+
+    /// the frames subscribing to trades and quotes of `symbols`, sent right
+    /// after connecting
+    fn subscriptions(&self, symbols: &[String]) -> Vec<String>;
+
+    /// the frames ending the subscriptions, sent on shutdown
+    fn unsubscriptions(&self, symbols: &[String]) -> Vec<String>;
+
+    /// The trades and quotes in a frame, acks and heartbeats have none.
+    /// `received` stands in for exchanges that don't timestamp quotes.
+    fn parse(&self, frame: &str, received: DateTime<Utc>) -> Result<Vec<Normalized>, String>;
+}
+
+/// The exchanges with an adapter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ExchangeKind {
+    Coinbase,
+    Binance,
+    Kraken,
+}
+
+/// A market on an exchange, parsed from 'binance:BTCUSDT'
+#[derive(Debug, Clone)]
+pub struct ExchangeMarket {
+    pub exchange: ExchangeKind,
+    pub symbol: String,
+}
+
+impl FromStr for ExchangeMarket {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (exchange, symbol) = s.split_once(':').ok_or_else(|| {
+            format!("expected '<exchange>:<symbol>' e.g. 'binance:BTCUSDT', got '{}'", s)
+        })?;
+
+        let exchange = match exchange.to_ascii_lowercase().as_str() {
+            "coinbase" => ExchangeKind::Coinbase,
+            "binance" => ExchangeKind::Binance,
+            "kraken" => ExchangeKind::Kraken,
+            _ => {
+                return Err(format!(
+                    "unknown exchange '{}', expected coinbase, binance or kraken",
+                    exchange
+                ))
+            }
+        };
+        if symbol.is_empty() {
+            return Err(format!("missing symbol in '{}'", s));
+        }
+
+        Ok(Self {
+            exchange,
+            symbol: symbol.to_owned(),
+        })
+    }
+}
+
+/// Keeps a connection to an exchange subscribed to `symbols`, reconnecting
+/// with exponential backoff, and sends every trade and quote to `tx` until
+/// `shutdown` is set
+pub fn spawn(
+    adapter: Arc<dyn ExchangeAdapter>,
+    symbols: Vec<String>,
+    backoff: Backoff,
+    tx: UnboundedSender<FeedEvent>,
+    shutdown: watch::Receiver<bool>,
+) {
+    let url = adapter.url().to_owned();
+    let session = ExchangeSession {
+        adapter,
+        symbols,
+        tx,
+    };
+    tokio::spawn(websocket::run(url, session, backoff, shutdown));
+}
+
+struct ExchangeSession {
+    adapter: Arc<dyn ExchangeAdapter>,
+    symbols: Vec<String>,
+    tx: UnboundedSender<FeedEvent>,
+}
+
+impl Session for ExchangeSession {
+    fn name(&self) -> &str {
+        self.adapter.name()
+    }
+
+    fn subscriptions(&mut self) -> BoxFuture<'_, Option<Vec<String>>> {
+        let subscriptions = self.adapter.subscriptions(&self.symbols);
+        Box::pin(future::ready((!self.tx.is_closed()).then_some(subscriptions)))
+    }
+
+    fn unsubscriptions(&self) -> Vec<String> {
+        self.adapter.unsubscriptions(&self.symbols)
+    }
+
+    fn received(&mut self, text: String) -> Flow {
+        let received = Utc::now();
+        let parsed = self.adapter.parse(&text, received);
+        if let Err(e) = &parsed {
+            println!("Failed parsing {} frame: {}: {}", self.adapter.name(), e, text);
+        }
+        let frame = Frame {
+            received,
+            channel: self.adapter.name(),
+            text,
+        };
+        if self.tx.send(FeedEvent::Frame(frame)).is_err() {
+            return Flow::Stop;
+        }
+
+        match parsed {
+            Ok(normalized) => {
+                for event in normalized {
+                    if self.tx.send(FeedEvent::Normalized(event)).is_err() {
+                        return Flow::Stop;
+                    }
+                }
+                Flow::Healthy
+            }
+            Err(_) => Flow::Continue,
+        }
+    }
+}
+
+/// Parses a decimal string, the exchanges send prices and sizes as strings
+fn decimal(value: &serde_json::Value, field: &str) -> Result<f64, String> {
+    value
+        .as_str()
+        .ok_or_else(|| format!("missing {}", field))?
+        .parse()
+        .map_err(|_| format!("invalid {}: {}", field, value))
+}
+
+/// Parses unix seconds with a fraction, like '1534614057.321597'
+fn unix_seconds(value: &serde_json::Value, field: &str) -> Result<DateTime<Utc>, String> {
+    let invalid = || format!("invalid {}: {}", field, value);
+    let text = value.as_str().ok_or_else(invalid)?;
+
+    let (secs, fraction) = text.split_once('.').unwrap_or((text, "0"));
+    let secs: i64 = secs.parse().map_err(|_| invalid())?;
+    // pad or cut the fraction to nanoseconds
+    let nanos: String = fraction.chars().chain(std::iter::repeat('0')).take(9).collect();
+    let nanos: u32 = nanos.parse().map_err(|_| invalid())?;
+
+    DateTime::<Utc>::from_timestamp(secs, nanos).ok_or_else(invalid)
+}
@@ -0,0 +1,725 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use coinbase_pro_rs::structs::wsfeed::*;
+use feed_schema::Normalized;
+use futures::future::{self, BoxFuture};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::watch;
+
+use crate::metrics;
+use crate::user::{self, Credentials};
+use crate::websocket::{self, Flow, Session, Update};
+
+/// Exponential backoff used between websocket reconnects
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    /// Returns the delay to wait now and doubles it for next time
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    /// Starts over from the initial delay, called once a connection is healthy
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+/// Marker published when the exchange sequence for a product jumps, meaning
+/// messages were lost (e.g. while reconnecting)
+#[derive(Debug, Clone, Serialize)]
+pub struct SequenceGap {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub product_id: String,
+    /// last sequence seen before the gap
+    pub last_sequence: usize,
+    /// first sequence seen after the gap
+    pub sequence: usize,
+    /// number of sequence numbers that never arrived
+    pub missing: usize,
+}
+
+/// Tracks the last exchange sequence number seen per product.
+///
+/// Sequence numbers are shared by every channel of a product, so a channel
+/// that only carries some events (like `ticker`) legitimately skips numbers.
+/// Unless `contiguous` is set, a jump is only reported for the first message
+/// after a reconnect, which is where data can actually be lost.
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    contiguous: bool,
+    last: HashMap<String, usize>,
+    resumed: HashSet<String>,
+}
+
+impl SequenceTracker {
+    pub fn new(contiguous: bool) -> Self {
+        Self {
+            contiguous,
+            ..Default::default()
+        }
+    }
+
+    /// Flags every known product so the next sequence is checked for a gap
+    pub fn reconnected(&mut self) {
+        self.resumed.extend(self.last.keys().cloned());
+    }
+
+    /// Records `sequence` for `product_id` and returns a gap if numbers were lost.
+    /// Stale or duplicate sequences are ignored.
+    pub fn observe(&mut self, product_id: &str, sequence: usize) -> Option<SequenceGap> {
+        let resumed = self.resumed.remove(product_id);
+
+        let last = match self.last.get_mut(product_id) {
+            Some(last) => last,
+            None => {
+                self.last.insert(product_id.to_owned(), sequence);
+                return None;
+            }
+        };
+
+        if sequence <= *last {
+            return None;
+        }
+
+        let gap = if sequence > *last + 1 && (resumed || self.contiguous) {
+            Some(SequenceGap {
+                kind: "gap",
+                product_id: product_id.to_owned(),
+                last_sequence: *last,
+                sequence,
+                missing: sequence - *last - 1,
+            })
+        } else {
+            None
+        };
+
+        *last = sequence;
+        gap
+    }
+}
+
+/// Websocket channels the producer can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum Channel {
+    Ticker,
+    Matches,
+    Level2,
+    Full,
+    Heartbeat,
+    Status,
+    /// our own orders and fills, needs api credentials
+    User,
+    /// normalized trades from the exchange adapters, see --exchange
+    #[value(skip)]
+    Trades,
+    /// normalized quotes from the exchange adapters
+    #[value(skip)]
+    Quotes,
+}
+
+impl Channel {
+    /// The channel name as coinbase spells it
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Ticker => "ticker",
+            Channel::Matches => "matches",
+            Channel::Level2 => "level2",
+            Channel::Full => "full",
+            Channel::Heartbeat => "heartbeat",
+            Channel::Status => "status",
+            Channel::User => "user",
+            Channel::Trades => "trades",
+            Channel::Quotes => "quotes",
+        }
+    }
+
+    /// The `coinbase_pro_rs` channel, `status` and `user` are subscribed to
+    /// on connections of their own
+    fn channel_type(&self) -> Option<ChannelType> {
+        match self {
+            Channel::Ticker => Some(ChannelType::Ticker),
+            Channel::Matches => Some(ChannelType::Matches),
+            Channel::Level2 => Some(ChannelType::Level2),
+            Channel::Full => Some(ChannelType::Full),
+            Channel::Heartbeat => Some(ChannelType::Heartbeat),
+            Channel::Status | Channel::User => None,
+            Channel::Trades | Channel::Quotes => None,
+        }
+    }
+}
+
+/// Trading status of a single product from the `status` channel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProductStatus {
+    pub id: String,
+    pub status: String,
+    #[serde(default)]
+    pub status_message: Option<String>,
+    // keep everything else coinbase sends so it can be published as is
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A `status` channel message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub products: Vec<ProductStatus>,
+    #[serde(default)]
+    pub currencies: Vec<serde_json::Value>,
+}
+
+/// A websocket frame as the exchange sent it, before it's parsed
+#[derive(Debug)]
+pub struct Frame {
+    pub received: DateTime<Utc>,
+    /// the channel of the message, or the connection it arrived on when it
+    /// isn't a channel message (like subscription acks or errors)
+    pub channel: &'static str,
+    pub text: String,
+}
+
+/// What the feed hands to its consumer
+#[derive(Debug)]
+pub enum FeedEvent {
+    /// a frame from the exchange, ahead of the events parsed from it
+    Frame(Frame),
+    /// a message from the exchange
+    Message(Message),
+    /// a message from the `status` channel
+    Status(Status),
+    /// a message from the authenticated `user` channel
+    User(serde_json::Value),
+    /// a trade or quote from an exchange adapter
+    Normalized(Normalized),
+    /// sequence numbers were lost before the next message of a product
+    Gap(SequenceGap),
+}
+
+/// Starts the websocket feed for `channels` on the markets in `products`,
+/// sending its events to `tx`. The feed follows changes of `products` on the
+/// open connections. The `status` and `user` channels, if requested, run on
+/// connections of their own, `user` signed with `credentials`. Once
+/// `shutdown` is set every connection is closed and `tx` dropped.
+pub fn spawn(
+    uri: &str,
+    products: watch::Receiver<Vec<String>>,
+    channels: &[Channel],
+    credentials: Option<Credentials>,
+    backoff: Backoff,
+    tx: UnboundedSender<FeedEvent>,
+    shutdown: watch::Receiver<bool>,
+) {
+    if let (true, Some(credentials)) = (channels.contains(&Channel::User), credentials) {
+        tokio::spawn(user::run(
+            uri.to_owned(),
+            products.clone(),
+            credentials,
+            backoff.clone(),
+            tx.clone(),
+            shutdown.clone(),
+        ));
+    }
+
+    let feed_channels: Vec<Channel> = channels
+        .iter()
+        .copied()
+        .filter(|channel| channel.channel_type().is_some())
+        .collect();
+    if !feed_channels.is_empty() {
+        let session = FeedSession::new(products, feed_channels, tx.clone());
+        tokio::spawn(websocket::run(uri.to_owned(), session, backoff.clone(), shutdown.clone()));
+    }
+
+    if channels.contains(&Channel::Status) {
+        let session = StatusSession { tx };
+        tokio::spawn(websocket::run(uri.to_owned(), session, backoff, shutdown));
+    }
+}
+
+/// Keeps a connection subscribed to the feed `channels` of the markets in
+/// `products`, resubscribing to them after every reconnect. When `products`
+/// changes the added markets are subscribed and the dropped ones unsubscribed
+/// on the open connection, the others keep streaming. Every frame is sent to
+/// `tx` as received, followed by any sequence gap it revealed and the parsed
+/// message.
+struct FeedSession {
+    products: watch::Receiver<Vec<String>>,
+    channels: Vec<Channel>,
+    tx: UnboundedSender<FeedEvent>,
+    sequences: SequenceTracker,
+    /// the markets subscribed on the open connection
+    product_ids: Vec<String>,
+    /// the markets never change once nothing can update them
+    fixed: bool,
+}
+
+impl FeedSession {
+    fn new(
+        products: watch::Receiver<Vec<String>>,
+        channels: Vec<Channel>,
+        tx: UnboundedSender<FeedEvent>,
+    ) -> Self {
+        Self {
+            products,
+            // the full channel carries every sequence number of a product
+            sequences: SequenceTracker::new(channels.contains(&Channel::Full)),
+            channels,
+            tx,
+            product_ids: Vec::new(),
+            fixed: false,
+        }
+    }
+
+    fn frame(&self, kind: &str, product_ids: &[&String]) -> String {
+        let names: Vec<&str> = self.channels.iter().map(Channel::name).collect();
+        serde_json::json!({
+            "type": kind,
+            "product_ids": product_ids,
+            "channels": names,
+        })
+        .to_string()
+    }
+
+    /// Frames subscribing the markets in `updated` but not on the connection
+    /// and unsubscribing the ones dropped
+    fn update(&self, updated: &[String]) -> Vec<String> {
+        let current = &self.product_ids;
+        let added: Vec<&String> = updated.iter().filter(|p| !current.contains(p)).collect();
+        let removed: Vec<&String> = current.iter().filter(|p| !updated.contains(p)).collect();
+
+        let mut frames = Vec::new();
+        for (kind, product_ids) in [("subscribe", added), ("unsubscribe", removed)] {
+            if product_ids.is_empty() {
+                continue;
+            }
+            frames.push(self.frame(kind, &product_ids));
+            let product_ids: Vec<&str> = product_ids.iter().map(|p| p.as_str()).collect();
+            println!("{}d {}", kind, product_ids.join(","));
+        }
+        frames
+    }
+}
+
+impl Session for FeedSession {
+    fn name(&self) -> &str {
+        "feed"
+    }
+
+    fn subscriptions(&mut self) -> BoxFuture<'_, Option<Vec<String>>> {
+        Box::pin(async move {
+            loop {
+                if self.tx.is_closed() {
+                    return None;
+                }
+                let product_ids = self.products.borrow_and_update().clone();
+                if product_ids.is_empty() {
+                    // nothing to subscribe to until a market is discovered
+                    if self.fixed || self.products.changed().await.is_err() {
+                        println!("no markets to subscribe to");
+                        return None;
+                    }
+                    continue;
+                }
+
+                println!("subscribing to the feed: {}", product_ids.join(","));
+                let subscribe = self.frame("subscribe", &product_ids.iter().collect::<Vec<_>>());
+                self.product_ids = product_ids;
+                return Some(vec![subscribe]);
+            }
+        })
+    }
+
+    fn updated(&mut self) -> BoxFuture<'_, Update> {
+        Box::pin(async move {
+            if self.fixed || self.products.changed().await.is_err() {
+                self.fixed = true;
+                return future::pending().await;
+            }
+
+            let updated = self.products.borrow_and_update().clone();
+            if updated.is_empty() {
+                // a connection without subscriptions gets closed
+                println!("no markets left, disconnecting");
+                return Update::Resubscribe;
+            }
+            let frames = self.update(&updated);
+            self.product_ids = updated;
+            Update::Send(frames)
+        })
+    }
+
+    fn unsubscriptions(&self) -> Vec<String> {
+        vec![self.frame("unsubscribe", &self.product_ids.iter().collect::<Vec<_>>())]
+    }
+
+    fn received(&mut self, text: String) -> Flow {
+        let parsed = serde_json::from_str::<Message>(&text);
+        let channel = match &parsed {
+            Ok(msg) => message_channel(msg).map_or("feed", |channel| channel.name()),
+            Err(_) => "feed",
+        };
+        let frame = Frame {
+            received: Utc::now(),
+            channel,
+            text,
+        };
+        if self.tx.send(FeedEvent::Frame(frame)).is_err() {
+            return Flow::Stop;
+        }
+
+        let msg = match parsed {
+            Ok(msg) => msg,
+            // a message the library can't parse, the connection is still fine
+            Err(e) => {
+                println!("Failed parsing message: {}", e);
+                return Flow::Continue;
+            }
+        };
+
+        if let Some((product_id, sequence)) = message_sequence(&msg) {
+            metrics::LAST_SEQUENCE
+                .with_label_values(&[&product_id])
+                .set(sequence as i64);
+
+            if let Some(gap) = self.sequences.observe(&product_id, sequence) {
+                println!(
+                    "{}: sequence gap {} -> {}, {} missing",
+                    gap.product_id, gap.last_sequence, gap.sequence, gap.missing
+                );
+                let _ = self.tx.send(FeedEvent::Gap(gap));
+            }
+        }
+
+        if self.tx.send(FeedEvent::Message(msg)).is_err() {
+            return Flow::Stop;
+        }
+        Flow::Healthy
+    }
+
+    fn disconnected(&mut self) {
+        self.sequences.reconnected();
+    }
+}
+
+/// A connection subscribed to the `status` channel, which
+/// `coinbase_pro_rs` does not know about
+struct StatusSession {
+    tx: UnboundedSender<FeedEvent>,
+}
+
+fn status_frame(kind: &str) -> String {
+    serde_json::json!({
+        "type": kind,
+        "channels": [{ "name": "status" }],
+    })
+    .to_string()
+}
+
+impl Session for StatusSession {
+    fn name(&self) -> &str {
+        "status"
+    }
+
+    fn subscriptions(&mut self) -> BoxFuture<'_, Option<Vec<String>>> {
+        let subscribe = (!self.tx.is_closed()).then(|| vec![status_frame("subscribe")]);
+        Box::pin(future::ready(subscribe))
+    }
+
+    fn unsubscriptions(&self) -> Vec<String> {
+        vec![status_frame("unsubscribe")]
+    }
+
+    fn received(&mut self, text: String) -> Flow {
+        // subscriptions acks and errors share the connection
+        let status = match serde_json::from_str::<Status>(&text) {
+            Ok(status) if status.kind == "status" => Some(status),
+            _ => {
+                println!("{}", text);
+                None
+            }
+        };
+        let frame = Frame {
+            received: Utc::now(),
+            channel: Channel::Status.name(),
+            text,
+        };
+        if self.tx.send(FeedEvent::Frame(frame)).is_err() {
+            return Flow::Stop;
+        }
+
+        match status {
+            Some(status) => {
+                if self.tx.send(FeedEvent::Status(status)).is_err() {
+                    return Flow::Stop;
+                }
+                Flow::Healthy
+            }
+            None => Flow::Continue,
+        }
+    }
+}
+
+/// Product and exchange sequence of a message, when it carries one
+pub fn message_sequence(msg: &Message) -> Option<(String, usize)> {
+    match msg {
+        Message::Ticker(Ticker::Full {
+            product_id,
+            sequence,
+            ..
+        })
+        | Message::Ticker(Ticker::Empty {
+            product_id,
+            sequence,
+            ..
+        }) => Some((product_id.clone(), *sequence)),
+        Message::Match(m) => Some((m.product_id.clone(), m.sequence)),
+        Message::Full(full) => {
+            let header = full_header(full);
+            Some((header.product_id.to_owned(), header.sequence? as usize))
+        }
+        _ => None,
+    }
+}
+
+/// What every `full` channel message carries, some `done` messages have no
+/// sequence and `activate` ones neither a sequence nor a time
+pub struct FullHeader<'a> {
+    pub product_id: &'a str,
+    pub sequence: Option<u64>,
+    pub time: Option<DateTime<Utc>>,
+}
+
+pub fn full_header(full: &Full) -> FullHeader<'_> {
+    match full {
+        Full::Received(Received::Limit {
+            product_id,
+            sequence,
+            time,
+            ..
+        })
+        | Full::Received(Received::Market {
+            product_id,
+            sequence,
+            time,
+            ..
+        })
+        | Full::Open(Open {
+            product_id,
+            sequence,
+            time,
+            ..
+        })
+        | Full::Match(Match {
+            product_id,
+            sequence,
+            time,
+            ..
+        })
+        | Full::Change(Change {
+            product_id,
+            sequence,
+            time,
+            ..
+        }) => FullHeader {
+            product_id,
+            sequence: Some(*sequence as u64),
+            time: Some(*time),
+        },
+        Full::Done(Done::Limit {
+            product_id,
+            sequence,
+            time,
+            ..
+        }) => FullHeader {
+            product_id,
+            sequence: sequence.map(|sequence| sequence as u64),
+            time: Some(*time),
+        },
+        Full::Done(Done::Market {
+            product_id,
+            sequence,
+            time,
+            ..
+        }) => FullHeader {
+            product_id,
+            sequence: Some(*sequence as u64),
+            time: Some(*time),
+        },
+        Full::Activate(Activate { product_id, .. }) => FullHeader {
+            product_id,
+            sequence: None,
+            time: None,
+        },
+    }
+}
+
+/// Exchange sequence of a `full` channel message
+pub fn full_sequence(full: &Full) -> Option<u64> {
+    full_header(full).sequence
+}
+
+pub fn full_product_id(full: &Full) -> Option<String> {
+    Some(full_header(full).product_id.to_owned())
+}
+
+/// The channel a message was delivered on
+pub fn message_channel(msg: &Message) -> Option<Channel> {
+    match msg {
+        Message::Ticker(_) => Some(Channel::Ticker),
+        Message::Match(_) => Some(Channel::Matches),
+        Message::Level2(_) => Some(Channel::Level2),
+        Message::Full(_) => Some(Channel::Full),
+        Message::Heartbeat { .. } => Some(Channel::Heartbeat),
+        _ => None,
+    }
+}
+
+/// When the exchange says a message happened
+pub fn message_time(msg: &Message) -> Option<DateTime<Utc>> {
+    match msg {
+        Message::Ticker(Ticker::Full { time, .. }) => Some(*time),
+        Message::Match(m) => Some(m.time),
+        Message::Heartbeat { time, .. } => Some(*time),
+        Message::Full(full) => full_header(full).time,
+        _ => None,
+    }
+}
+
+/// The product a message belongs to
+pub fn message_product_id(msg: &Message) -> Option<String> {
+    match msg {
+        Message::Ticker(ticker) => Some(ticker_product_id(ticker).to_owned()),
+        Message::Match(m) => Some(m.product_id.clone()),
+        Message::Level2(Level2::Snapshot { product_id, .. })
+        | Message::Level2(Level2::L2update { product_id, .. }) => Some(product_id.clone()),
+        Message::Full(full) => full_product_id(full),
+        Message::Heartbeat { product_id, .. } => Some(product_id.clone()),
+        _ => None,
+    }
+}
+
+/// The trade a ticker or match message reports
+pub fn message_trade_id(msg: &Message) -> Option<u64> {
+    match msg {
+        Message::Ticker(Ticker::Full { trade_id, .. }) => Some(*trade_id as u64),
+        Message::Match(m) => Some(m.trade_id as u64),
+        _ => None,
+    }
+}
+
+/// The message as published downstream
+pub fn message_payload(msg: &Message) -> Option<String> {
+    let data = match msg {
+        Message::Ticker(ticker) => serde_json::to_string(ticker),
+        Message::Match(m) => serde_json::to_string(m),
+        Message::Level2(level2) => serde_json::to_string(level2),
+        Message::Full(full) => serde_json::to_string(full),
+        Message::Heartbeat {
+            sequence,
+            last_trade_id,
+            product_id,
+            time,
+        } => serde_json::to_string(&serde_json::json!({
+            "type": "heartbeat",
+            "sequence": sequence,
+            "last_trade_id": last_trade_id,
+            "product_id": product_id,
+            "time": time,
+        })),
+        _ => return None,
+    };
+    data.ok()
+}
+
+pub fn ticker_product_id(ticker: &Ticker) -> &str {
+    match ticker {
+        Ticker::Full { product_id, .. } => product_id,
+        Ticker::Empty { product_id, .. } => product_id,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matched(sequence: usize, trade_id: usize) -> Message {
+        let frame = serde_json::json!({
+            "type": "match",
+            "trade_id": trade_id,
+            "sequence": sequence,
+            "maker_order_id": "ac928c66-ca53-498f-9c13-a110027a60e8",
+            "taker_order_id": "132fb6ae-456b-4654-b4e0-d681ac05cea1",
+            "time": "2023-10-15T14:00:00.250000Z",
+            "product_id": "BTC-USD",
+            "size": "0.10000000",
+            "price": "27000.00",
+            "side": "buy",
+        });
+        Message::Match(serde_json::from_value(frame).unwrap())
+    }
+
+    fn observe(sequences: &mut SequenceTracker, msg: &Message) -> Option<SequenceGap> {
+        let (product_id, sequence) = message_sequence(msg).expect("matches are sequenced");
+        sequences.observe(&product_id, sequence)
+    }
+
+    #[test]
+    fn finds_gaps_on_the_matches_channel() {
+        let mut sequences = SequenceTracker::new(false);
+
+        // other events of the product take the numbers in between
+        assert!(observe(&mut sequences, &matched(100, 1)).is_none());
+        assert!(observe(&mut sequences, &matched(104, 2)).is_none());
+
+        sequences.reconnected();
+        let gap = observe(&mut sequences, &matched(180, 3)).expect("a gap across the reconnect");
+        assert_eq!((gap.last_sequence, gap.sequence, gap.missing), (104, 180, 75));
+    }
+
+    #[test]
+    fn reads_the_header_of_full_messages() {
+        let msg: Message = serde_json::from_value(serde_json::json!({
+            "type": "open",
+            "time": "2023-10-15T14:00:00.250000Z",
+            "product_id": "BTC-USD",
+            "sequence": 42,
+            "order_id": "ac928c66-ca53-498f-9c13-a110027a60e8",
+            "price": "27000.00",
+            "remaining_size": "0.5",
+            "side": "sell",
+        }))
+        .unwrap();
+        let Message::Full(open) = &msg else {
+            panic!("not a full channel message: {:?}", msg);
+        };
+        let header = full_header(open);
+        assert_eq!((header.product_id, header.sequence), ("BTC-USD", Some(42)));
+        assert_eq!(header.time, Some("2023-10-15T14:00:00.25Z".parse().unwrap()));
+
+        assert_eq!(message_sequence(&msg), Some(("BTC-USD".to_string(), 42)));
+        assert_eq!(message_product_id(&msg).as_deref(), Some("BTC-USD"));
+    }
+}
@@ -0,0 +1,672 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use coinbase_pro_rs::structs::public::BookRecordL3;
+use coinbase_pro_rs::structs::reqs::OrderSide;
+use coinbase_pro_rs::structs::wsfeed::{Change, Done, Full, Match, Open};
+use coinbase_pro_rs::{ASync, Public};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::book::Price;
+use crate::feed::{full_product_id, full_sequence};
+use crate::shutdown;
+
+/// How long to wait between snapshot requests of a product, whether the
+/// last one failed or was behind the feed
+const RESYNC_RETRY: Duration = Duration::from_secs(1);
+
+/// Max messages of a product buffered while its snapshot loads, the oldest
+/// are dropped beyond it and a snapshot has to be newer than what's left
+const MAX_BUFFERED: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+impl From<&OrderSide> for Side {
+    fn from(side: &OrderSide) -> Self {
+        match side {
+            OrderSide::Buy => Side::Buy,
+            OrderSide::Sell => Side::Sell,
+        }
+    }
+}
+
+/// An order in a level 3 snapshot
+#[derive(Debug, Clone)]
+pub struct SnapshotOrder {
+    pub order_id: String,
+    pub price: f64,
+    pub size: f64,
+}
+
+/// Every resting order of a product at `sequence`, orders are in queue order
+#[derive(Debug, Clone)]
+pub struct L3Snapshot {
+    pub sequence: u64,
+    pub bids: Vec<SnapshotOrder>,
+    pub asks: Vec<SnapshotOrder>,
+}
+
+/// Where the book is (re)loaded from when it's out of sync
+pub trait SnapshotSource: Send + Sync + 'static {
+    fn snapshot<'a>(&'a self, product_id: &'a str) -> BoxFuture<'a, Result<L3Snapshot, String>>;
+}
+
+/// Loads level 3 snapshots from the coinbase REST api, `uri` can point at a
+/// local mock of `/products/<id>/book?level=3`
+pub struct RestSnapshots {
+    client: Public<ASync>,
+}
+
+impl RestSnapshots {
+    pub fn new(uri: &str) -> Self {
+        Self {
+            client: Public::new(uri),
+        }
+    }
+}
+
+impl SnapshotSource for RestSnapshots {
+    fn snapshot<'a>(&'a self, product_id: &'a str) -> BoxFuture<'a, Result<L3Snapshot, String>> {
+        Box::pin(async move {
+            let book = self
+                .client
+                .get_book::<BookRecordL3>(product_id)
+                .await
+                .map_err(|e| e.to_string())?;
+
+            let orders = |records: Vec<BookRecordL3>| {
+                records
+                    .into_iter()
+                    .map(|r| SnapshotOrder {
+                        order_id: r.order_id.to_string(),
+                        price: r.price,
+                        size: r.size,
+                    })
+                    .collect()
+            };
+
+            Ok(L3Snapshot {
+                sequence: book.sequence as u64,
+                bids: orders(book.bids),
+                asks: orders(book.asks),
+            })
+        })
+    }
+}
+
+/// A message arrived out of order, the book can't be trusted anymore
+#[derive(Debug, Clone, Copy)]
+pub struct OutOfSequence {
+    pub expected: u64,
+    pub sequence: u64,
+}
+
+#[derive(Debug, Clone)]
+struct RestingOrder {
+    side: Side,
+    price: Price,
+    size: f64,
+}
+
+/// Where an order waits in its price level
+#[derive(Debug, Clone, Serialize)]
+pub struct QueuePosition {
+    pub order_id: String,
+    pub side: Side,
+    pub price: f64,
+    pub size: f64,
+    /// orders at the same price that fill first
+    pub orders_ahead: usize,
+    /// their total size
+    pub size_ahead: f64,
+}
+
+/// A summary of a level 3 book, levels are aggregated `[price, size]`
+#[derive(Debug, Clone, Serialize)]
+pub struct L3Summary {
+    pub product_id: String,
+    pub sequence: u64,
+    pub orders: usize,
+    pub bids: Vec<[f64; 2]>,
+    pub asks: Vec<[f64; 2]>,
+}
+
+/// A per order (level 3) book built from the `full` channel
+#[derive(Debug, Default)]
+pub struct L3Book {
+    sequence: u64,
+    orders: HashMap<String, RestingOrder>,
+    // order ids at each price in the order they were placed
+    bids: BTreeMap<Price, VecDeque<String>>,
+    asks: BTreeMap<Price, VecDeque<String>>,
+}
+
+impl L3Book {
+    pub fn from_snapshot(snapshot: &L3Snapshot) -> Self {
+        let mut book = Self {
+            sequence: snapshot.sequence,
+            ..Default::default()
+        };
+        for order in &snapshot.bids {
+            book.insert(order.order_id.clone(), Side::Buy, order.price, order.size);
+        }
+        for order in &snapshot.asks {
+            book.insert(order.order_id.clone(), Side::Sell, order.price, order.size);
+        }
+        book
+    }
+
+    /// The sequence of the last message applied
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Applies a `full` channel message. Messages from before the book's
+    /// sequence are skipped, a message that skips ahead is an error
+    pub fn apply(&mut self, full: &Full) -> Result<(), OutOfSequence> {
+        if let Some(sequence) = full_sequence(full) {
+            if sequence <= self.sequence {
+                return Ok(());
+            }
+            if sequence != self.sequence + 1 {
+                return Err(OutOfSequence {
+                    expected: self.sequence + 1,
+                    sequence,
+                });
+            }
+            self.sequence = sequence;
+        }
+
+        match full {
+            Full::Open(Open {
+                order_id,
+                side,
+                price,
+                remaining_size,
+                ..
+            }) => self.insert(order_id.to_string(), side.into(), *price, *remaining_size),
+            Full::Done(Done::Limit { order_id, .. })
+            | Full::Done(Done::Market { order_id, .. }) => self.remove(&order_id.to_string()),
+            Full::Match(Match {
+                maker_order_id,
+                size,
+                ..
+            }) => {
+                // the maker's done message removes it once it's filled
+                if let Some(order) = self.orders.get_mut(&maker_order_id.to_string()) {
+                    order.size = (order.size - size).max(0.0);
+                }
+            }
+            Full::Change(Change {
+                order_id, new_size, ..
+            }) => {
+                if let Some(order) = self.orders.get_mut(&order_id.to_string()) {
+                    order.size = *new_size;
+                }
+            }
+            // received and activate don't touch resting orders
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Where an order sits in the queue at its price
+    pub fn queue_position(&self, order_id: &str) -> Option<QueuePosition> {
+        let order = self.orders.get(order_id)?;
+        let queue = self.levels(order.side).get(&order.price)?;
+
+        let ahead: Vec<&RestingOrder> = queue
+            .iter()
+            .take_while(|id| id.as_str() != order_id)
+            .filter_map(|id| self.orders.get(id))
+            .collect();
+
+        Some(QueuePosition {
+            order_id: order_id.to_owned(),
+            side: order.side,
+            price: order.price.0,
+            size: order.size,
+            orders_ahead: ahead.len(),
+            size_ahead: ahead.iter().map(|o| o.size).sum(),
+        })
+    }
+
+    /// The best `depth` aggregated levels of a side
+    pub fn top(&self, side: Side, depth: usize) -> Vec<[f64; 2]> {
+        let aggregate = |(price, queue): (&Price, &VecDeque<String>)| {
+            let size = queue
+                .iter()
+                .filter_map(|id| self.orders.get(id))
+                .map(|o| o.size)
+                .sum();
+            [price.0, size]
+        };
+
+        match side {
+            Side::Buy => self.bids.iter().rev().take(depth).map(aggregate).collect(),
+            Side::Sell => self.asks.iter().take(depth).map(aggregate).collect(),
+        }
+    }
+
+    pub fn summary(&self, product_id: &str, depth: usize) -> L3Summary {
+        L3Summary {
+            product_id: product_id.to_owned(),
+            sequence: self.sequence,
+            orders: self.orders.len(),
+            bids: self.top(Side::Buy, depth),
+            asks: self.top(Side::Sell, depth),
+        }
+    }
+
+    fn levels(&self, side: Side) -> &BTreeMap<Price, VecDeque<String>> {
+        match side {
+            Side::Buy => &self.bids,
+            Side::Sell => &self.asks,
+        }
+    }
+
+    fn insert(&mut self, order_id: String, side: Side, price: f64, size: f64) {
+        let levels = match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        };
+        levels.entry(Price(price)).or_default().push_back(order_id.clone());
+        self.orders.insert(
+            order_id,
+            RestingOrder {
+                side,
+                price: Price(price),
+                size,
+            },
+        );
+    }
+
+    fn remove(&mut self, order_id: &str) {
+        // done messages also arrive for orders that never rested on the book
+        let order = match self.orders.remove(order_id) {
+            Some(order) => order,
+            None => return,
+        };
+
+        let levels = match order.side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        };
+        if let Some(queue) = levels.get_mut(&order.price) {
+            queue.retain(|id| id != order_id);
+            if queue.is_empty() {
+                levels.remove(&order.price);
+            }
+        }
+    }
+}
+
+/// Builds a book from a snapshot and the messages buffered while it loaded.
+/// Buffered messages the snapshot covers are skipped, and the snapshot is
+/// out of sequence when it's older than the oldest one.
+pub fn replay<'a>(
+    snapshot: &L3Snapshot,
+    buffered: impl IntoIterator<Item = &'a Full>,
+) -> Result<L3Book, OutOfSequence> {
+    let mut book = L3Book::from_snapshot(snapshot);
+    for full in buffered {
+        book.apply(full)?;
+    }
+    Ok(book)
+}
+
+/// The messages of a product without a book, waiting for a snapshot
+#[derive(Default)]
+struct Resync {
+    buffered: VecDeque<Full>,
+    last_attempt: Option<Instant>,
+}
+
+impl Resync {
+    fn push(&mut self, full: Full) {
+        if self.buffered.len() == MAX_BUFFERED {
+            self.buffered.pop_front();
+        }
+        self.buffered.push_back(full);
+    }
+
+    /// Whether it's time to request another snapshot
+    fn due(&self) -> bool {
+        match self.last_attempt {
+            Some(at) => at.elapsed() >= RESYNC_RETRY,
+            None => true,
+        }
+    }
+}
+
+type Books = Arc<RwLock<HashMap<String, L3Book>>>;
+
+/// What the book task works through, in order
+enum Input {
+    Message(Box<Full>),
+    /// the product was unsubscribed
+    Remove(String),
+}
+
+/// Feeds `full` channel messages to the level 3 books and answers queries
+/// about them. Cheap to clone.
+#[derive(Clone)]
+pub struct L3Handle {
+    tx: UnboundedSender<Input>,
+    books: Books,
+}
+
+impl L3Handle {
+    /// Queues a message for the books
+    pub fn send(&self, full: Full) {
+        let _ = self.tx.send(Input::Message(Box::new(full)));
+    }
+
+    /// Drops the book of a product that was unsubscribed, after the
+    /// messages queued before
+    pub fn remove(&self, product_id: &str) {
+        let _ = self.tx.send(Input::Remove(product_id.to_owned()));
+    }
+
+    pub fn queue_position(&self, product_id: &str, order_id: &str) -> Option<QueuePosition> {
+        let books = self.books.read().unwrap();
+        books.get(product_id)?.queue_position(order_id)
+    }
+
+    pub fn summary(&self, product_id: &str, depth: usize) -> Option<L3Summary> {
+        let books = self.books.read().unwrap();
+        Some(books.get(product_id)?.summary(product_id, depth))
+    }
+}
+
+/// Starts the level 3 books. A product's book is loaded from `source` on its
+/// first message and reloaded whenever a message arrives out of sequence.
+/// Until a snapshot loads its messages are buffered, and those newer than
+/// the snapshot are applied to it. A snapshot that failed or is older than
+/// the buffered messages is requested again after RESYNC_RETRY. The task
+/// ends once `shutdown` is set.
+pub fn spawn(
+    source: impl SnapshotSource,
+    mut shutdown: watch::Receiver<bool>,
+) -> (L3Handle, JoinHandle<()>) {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Input>();
+    let books: Books = Arc::default();
+
+    let task_books = books.clone();
+    let books_task = async move {
+        let mut resyncs: HashMap<String, Resync> = HashMap::new();
+
+        while let Some(input) = rx.recv().await {
+            let full = match input {
+                Input::Message(full) => *full,
+                Input::Remove(product_id) => {
+                    resyncs.remove(&product_id);
+                    task_books.write().unwrap().remove(&product_id);
+                    continue;
+                }
+            };
+            let product_id = match full_product_id(&full) {
+                Some(product_id) => product_id,
+                None => continue,
+            };
+
+            if !resyncs.contains_key(&product_id) {
+                let mut books = task_books.write().unwrap();
+                match books.get_mut(&product_id).map(|book| book.apply(&full)) {
+                    Some(Ok(())) => continue,
+                    Some(Err(gap)) => {
+                        println!(
+                            "{}: level 3 book out of sequence, expected {} got {}, resyncing",
+                            product_id, gap.expected, gap.sequence
+                        );
+                        // rather no book than a wrong one
+                        books.remove(&product_id);
+                    }
+                    None => {}
+                }
+            }
+
+            let resync = resyncs.entry(product_id.clone()).or_default();
+            resync.push(full);
+            if !resync.due() {
+                continue;
+            }
+            resync.last_attempt = Some(Instant::now());
+
+            match source.snapshot(&product_id).await {
+                Ok(snapshot) => match replay(&snapshot, &resync.buffered) {
+                    Ok(book) => {
+                        println!(
+                            "{}: level 3 book loaded at sequence {}, now at {}",
+                            product_id,
+                            snapshot.sequence,
+                            book.sequence()
+                        );
+                        resyncs.remove(&product_id);
+                        task_books.write().unwrap().insert(product_id, book);
+                    }
+                    Err(gap) => println!(
+                        "{}: level 3 snapshot at {} is behind the feed at {}, retrying",
+                        product_id, snapshot.sequence, gap.sequence
+                    ),
+                },
+                Err(e) => println!("{}: failed loading level 3 snapshot: {}", product_id, e),
+            }
+        }
+    };
+    let task = tokio::spawn(async move {
+        tokio::select! {
+            _ = books_task => {}
+            _ = shutdown::stopped(&mut shutdown) => {}
+        }
+    });
+
+    (L3Handle { tx, books }, task)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use coinbase_pro_rs::structs::wsfeed::Message;
+
+    use super::*;
+
+    /// Hands out the snapshots in order and counts the requests
+    struct FakeSnapshots {
+        snapshots: Mutex<VecDeque<L3Snapshot>>,
+        requests: Arc<AtomicUsize>,
+    }
+
+    impl SnapshotSource for FakeSnapshots {
+        fn snapshot<'a>(&'a self, _: &'a str) -> BoxFuture<'a, Result<L3Snapshot, String>> {
+            Box::pin(async move {
+                self.requests.fetch_add(1, Ordering::SeqCst);
+                let snapshot = self.snapshots.lock().unwrap().pop_front();
+                snapshot.ok_or_else(|| "no snapshot left".to_string())
+            })
+        }
+    }
+
+    /// The books, the snapshot request count and the sender keeping them running
+    fn books(snapshots: Vec<L3Snapshot>) -> (L3Handle, Arc<AtomicUsize>, watch::Sender<bool>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let source = FakeSnapshots {
+            snapshots: Mutex::new(snapshots.into()),
+            requests: requests.clone(),
+        };
+        let (stop, shutdown) = watch::channel(false);
+        let (handle, _) = spawn(source, shutdown);
+        (handle, requests, stop)
+    }
+
+    fn id(n: u64) -> String {
+        format!("00000000-0000-0000-0000-{:012}", n)
+    }
+
+    fn snapshot(sequence: u64, bids: &[(u64, f64)]) -> L3Snapshot {
+        L3Snapshot {
+            sequence,
+            bids: bids
+                .iter()
+                .map(|(n, size)| SnapshotOrder {
+                    order_id: id(*n),
+                    price: 100.0,
+                    size: *size,
+                })
+                .collect(),
+            asks: Vec::new(),
+        }
+    }
+
+    /// A full channel message the way the feed parses it
+    fn full(frame: serde_json::Value) -> Full {
+        match serde_json::from_value(frame).unwrap() {
+            Message::Full(full) => full,
+            Message::Match(m) => Full::Match(m),
+            other => panic!("not a full channel message: {:?}", other),
+        }
+    }
+
+    /// A bid for `size` at 100
+    fn open(sequence: u64, n: u64, size: f64) -> Full {
+        full(serde_json::json!({
+            "type": "open",
+            "time": "2023-10-15T14:00:00.000000Z",
+            "product_id": "BTC-USD",
+            "sequence": sequence,
+            "order_id": id(n),
+            "price": "100.00",
+            "remaining_size": size.to_string(),
+            "side": "buy",
+        }))
+    }
+
+    /// A fill of `size` of the resting bid `n`
+    fn matched(sequence: u64, n: u64, size: f64) -> Full {
+        full(serde_json::json!({
+            "type": "match",
+            "trade_id": sequence,
+            "sequence": sequence,
+            "maker_order_id": id(n),
+            "taker_order_id": id(999),
+            "time": "2023-10-15T14:00:00.000000Z",
+            "product_id": "BTC-USD",
+            "size": size.to_string(),
+            "price": "100.00",
+            "side": "buy",
+        }))
+    }
+
+    /// Waits until the book has applied `sequence`
+    async fn synced(handle: &L3Handle, sequence: u64) -> L3Summary {
+        let wait = async {
+            loop {
+                if let Some(summary) = handle.summary("BTC-USD", 10) {
+                    if summary.sequence >= sequence {
+                        return summary;
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .unwrap_or_else(|_| panic!("the book never reached sequence {}", sequence))
+    }
+
+    #[tokio::test]
+    async fn skips_messages_the_snapshot_covers() {
+        let (handle, requests, _stop) = books(vec![snapshot(10, &[(1, 1.0), (2, 2.0)])]);
+
+        // loads the snapshot, which already has this order
+        handle.send(open(9, 2, 2.0));
+        handle.send(open(11, 3, 3.0));
+        handle.send(matched(12, 1, 0.25));
+
+        let summary = synced(&handle, 12).await;
+        assert_eq!(summary.orders, 3);
+        assert_eq!(summary.bids, vec![[100.0, 5.75]]);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // behind the snapshot's orders, the first one partly filled
+        let position = handle.queue_position("BTC-USD", &id(3)).unwrap();
+        assert_eq!((position.orders_ahead, position.size_ahead), (2, 2.75));
+        let position = handle.queue_position("BTC-USD", &id(1)).unwrap();
+        assert_eq!((position.orders_ahead, position.size), (0, 0.75));
+    }
+
+    #[tokio::test]
+    async fn resyncs_after_a_gap() {
+        let (handle, requests, _stop) = books(vec![
+            snapshot(10, &[(1, 1.0)]),
+            snapshot(20, &[(1, 1.0), (2, 2.0)]),
+        ]);
+
+        handle.send(open(11, 2, 2.0));
+        synced(&handle, 11).await;
+
+        // 12 to 14 never arrived
+        handle.send(open(15, 3, 3.0));
+        handle.send(open(21, 4, 4.0));
+
+        let summary = synced(&handle, 21).await;
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        // the order opened at 15 was gone again by the second snapshot
+        assert!(handle.queue_position("BTC-USD", &id(3)).is_none());
+        assert_eq!(summary.orders, 3);
+        let position = handle.queue_position("BTC-USD", &id(4)).unwrap();
+        assert_eq!((position.orders_ahead, position.size_ahead), (2, 3.0));
+    }
+
+    #[tokio::test]
+    async fn retries_a_stale_snapshot_once_a_second() {
+        let (handle, requests, _stop) = books(vec![
+            snapshot(5, &[(1, 1.0)]),
+            snapshot(11, &[(1, 1.0), (2, 2.0)]),
+        ]);
+
+        // the first snapshot is older than the feed
+        handle.send(open(10, 2, 2.0));
+        handle.send(open(11, 3, 3.0));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert!(handle.summary("BTC-USD", 10).is_none());
+
+        tokio::time::sleep(RESYNC_RETRY).await;
+        handle.send(open(12, 4, 4.0));
+
+        // the second one covers 11 but not the buffered 12
+        let summary = synced(&handle, 12).await;
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert_eq!(summary.bids, vec![[100.0, 7.0]]);
+        assert!(handle.queue_position("BTC-USD", &id(3)).is_none());
+    }
+
+    #[tokio::test]
+    async fn drops_the_books_of_removed_products() {
+        let (handle, _, _stop) = books(vec![snapshot(10, &[(1, 1.0)])]);
+        handle.send(open(11, 2, 2.0));
+        synced(&handle, 11).await;
+
+        handle.remove("BTC-USD");
+        let removed = async {
+            while handle.summary("BTC-USD", 10).is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), removed).await.unwrap();
+    }
+}
@@ -0,0 +1,37 @@
+//! The coinbase pro market feed, published to kafka or any other [`Sink`].
+//!
+//! [`FeedPublisher`] runs the whole feed in process: the websockets, market
+//! discovery, order books, backfills and checkpoints. The `coinbase` binary
+//! is a command line on top of it.
+
+#[macro_use]
+extern crate lazy_static;
+
+mod backfill;
+mod book;
+mod checkpoint;
+mod conflate;
+mod dedup;
+pub mod discovery;
+mod envelope;
+pub mod exchange;
+pub mod feed;
+mod l3book;
+pub mod metrics;
+pub mod producer;
+mod publisher;
+pub mod recorder;
+pub mod replay;
+pub mod security;
+pub mod shutdown;
+pub mod sink;
+mod spool;
+pub mod topic;
+pub mod user;
+mod websocket;
+
+pub use feed::{Backoff, Channel};
+pub use feed_schema::Format;
+pub use publisher::{FeedControl, FeedHandle, FeedPublisher, FeedPublisherBuilder};
+pub use sink::{ChannelSink, PublishedRecord, Sink, SinkRecord};
+pub use topic::TopicTemplate;
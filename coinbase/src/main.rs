@@ -1,107 +1,472 @@
-use futures::StreamExt;
 //use coinbase_pro_rs::{WSFeed, CBError, WS_SANDBOX_URL, WS_URL};
-use clap::Parser;
-use coinbase_pro_rs::structs::wsfeed::*;
-use coinbase_pro_rs::{CBError, WSFeed, WS_URL};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use coinbase_pro_rs::{MAIN_URL, WS_URL};
 
+use chrono::{DateTime, Utc};
+use std::ffi::OsString;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::time::Duration;
 
-use kafka::error::Error as KafkaError;
-use kafka::producer::{Producer, Record, RequiredAcks};
+use coinbase::discovery::ProductFilter;
+use coinbase::exchange::{ExchangeMarket, BINANCE_URL, KRAKEN_URL};
+use coinbase::producer::{FeedProducer, ProducerConfig};
+use coinbase::recorder::{Compression, FeedRecorder};
+use coinbase::replay::{self, Speed};
+use coinbase::security::{KafkaSecurity, SecurityConfig};
+use coinbase::sink::{KafkaSink, NatsSink, SinkKind, StdoutSink};
+use coinbase::user::Credentials;
+use coinbase::{metrics, shutdown};
+use coinbase::{Backoff, Channel, FeedControl, FeedPublisher, Format, Sink, TopicTemplate};
+use tokio::signal::unix::{signal, SignalKind};
+
+mod config;
 
 /// A coinbase pro market feed kafka producer
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 struct Args {
-    /// The market to connect to e.g. 'BTC=USD'
-    #[arg(short, long)]
-    market: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// TOML or YAML file with any of the options below, keyed by long flag
+    /// name. COINBASE_<FLAG> variables override it, the command line
+    /// overrides both. SIGHUP reloads the markets from it
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// The markets to connect to e.g. 'BTC-USD'. Repeat the flag or pass a
+    /// comma separated list to subscribe to several markets on one websocket
+    #[arg(short, long, value_delimiter = ',')]
+    market: Vec<String>,
+    /// Also subscribe to every online market quoted in this currency e.g. 'USD'
+    #[arg(long)]
+    quote: Option<String>,
+    /// Also subscribe to every online market whose id matches this glob e.g. '*-USDC'
+    #[arg(long = "match")]
+    market_match: Option<String>,
+    /// Normalized trades and quotes of markets on any exchange e.g.
+    /// 'binance:BTCUSDT,kraken:XBT/USD,coinbase:BTC-USD', in the exchange's own
+    /// spelling. Exchanges: coinbase, binance and kraken
+    #[arg(long, value_delimiter = ',')]
+    exchange: Vec<ExchangeMarket>,
+    /// Topic template of the normalized trades and quotes, '{channel}' is
+    /// 'trades' or 'quotes'. Records are keyed by '<exchange>:<symbol>'
+    #[arg(long, default_value = "normalized-{channel}", value_parser = TopicTemplate::parse)]
+    normalized_topic: TopicTemplate,
+    /// Binance websocket for --exchange
+    #[arg(long, default_value = BINANCE_URL)]
+    binance_url: String,
+    /// Kraken websocket for --exchange
+    #[arg(long, default_value = KRAKEN_URL)]
+    kraken_url: String,
+    /// Seconds between refreshes of the discovered markets
+    #[arg(long, default_value_t = 300)]
+    products_refresh_secs: u64,
     /// Kafka broker defaults to 'localhost:9092' 
     #[arg(short, long, default_value = "localhost:9092")]
     broker: String,
-    /// Kafka topic 
-    #[arg(short, long)]
-    topic: String,
+    /// Kafka topic template, '{product}' and '{channel}' are replaced per
+    /// message e.g. 'coinbase-{product}-{channel}'. Records are keyed by
+    /// product id so each market stays ordered on one partition
+    #[arg(short, long, default_value = "coinbase-{channel}", value_parser = TopicTemplate::parse)]
+    topic: TopicTemplate,
+    #[command(flatten)]
+    security: KafkaSecurity,
+    /// Encoding of kafka records: json, msgpack, protobuf or avro, see the
+    /// schemas in feed-schema/schemas
+    #[arg(short, long, default_value = "json")]
+    format: Format,
+    /// The websocket channels to subscribe to. Repeat the flag or pass a
+    /// comma separated list
+    #[arg(short, long, value_enum, value_delimiter = ',', default_value = "ticker")]
+    channel: Vec<Channel>,
+    /// Private topic template for the authenticated user channel ('-c user'),
+    /// which needs COINBASE_API_KEY, COINBASE_API_SECRET and
+    /// COINBASE_API_PASSPHRASE or --secrets-file
+    #[arg(long, default_value = "coinbase-user-{product}", value_parser = TopicTemplate::parse)]
+    user_topic: TopicTemplate,
+    /// Json file with the api 'key', 'secret' and 'passphrase' for the user channel
+    #[arg(long)]
+    secrets_file: Option<PathBuf>,
+    /// Websocket feed to connect to
+    #[arg(long, default_value = WS_URL)]
+    ws_url: String,
+    /// Max records sent to kafka in one request
+    #[arg(long, default_value_t = 500)]
+    batch_size: usize,
+    /// Milliseconds to wait for more records before sending a partial batch
+    #[arg(long, default_value_t = 5)]
+    linger_ms: u64,
+    /// Max seconds to wait between websocket reconnect attempts
+    #[arg(long, default_value_t = 60)]
+    max_backoff_secs: u64,
+    /// Where to publish feed records. Repeat the flag or pass a comma
+    /// separated list to publish to several sinks
+    #[arg(short, long, value_enum, value_delimiter = ',', default_value = "kafka")]
+    sink: Vec<SinkKind>,
+    /// Record every websocket frame to hourly compressed files in this
+    /// directory, implies the file sink
+    #[arg(long)]
+    record_dir: Option<PathBuf>,
+    /// Compression of the recorded feed files
+    #[arg(long, value_enum, default_value = "gzip")]
+    record_compression: Compression,
+    /// Seconds of frames per compressed segment of a recording, the file is
+    /// readable up to the last finished segment
+    #[arg(long, default_value_t = 60)]
+    record_segment_secs: u64,
+    /// Nats server for the nats sink
+    #[arg(long, default_value = "nats://localhost:4222")]
+    nats_url: String,
+    /// Spool records to this directory while kafka is unavailable and send
+    /// them in order once it's back
+    #[arg(long)]
+    spool_dir: Option<PathBuf>,
+    /// Max size of the spool in megabytes, records are dropped beyond it
+    #[arg(long, default_value_t = 1024)]
+    spool_max_mb: u64,
+    /// Serve prometheus '/metrics' and '/healthz' on this address e.g. '0.0.0.0:9184'
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+    /// Seconds without any message before '/healthz' fails
+    #[arg(long, default_value_t = 30)]
+    health_timeout_secs: u64,
+    /// Build order books from the level2 channel and publish their top
+    /// levels to this topic template e.g. 'coinbase-{product}-book'
+    #[arg(long, value_parser = TopicTemplate::parse)]
+    book_topic: Option<TopicTemplate>,
+    /// Price levels per side in published book snapshots
+    #[arg(long, default_value_t = 10)]
+    book_depth: usize,
+    /// Milliseconds between published book snapshots
+    #[arg(long, default_value_t = 1000)]
+    book_interval_ms: u64,
+    /// Also publish at most one ticker per market every --conflate-interval-ms
+    /// to this topic template e.g. 'coinbase-{product}-ticker-1s', the latest
+    /// one with 'last_size' summed since the previous
+    #[arg(long, value_parser = TopicTemplate::parse)]
+    conflate_topic: Option<TopicTemplate>,
+    /// Milliseconds between conflated tickers of a market
+    #[arg(long, default_value_t = 1000)]
+    conflate_interval_ms: u64,
+    /// After a reconnect, recover the trades missed meanwhile from the REST
+    /// api and publish them before resuming the live feed
+    #[arg(long)]
+    backfill: bool,
+    /// Also backfill each market's trades since this time on startup e.g.
+    /// '2023-10-15T14:00:00Z', implies --backfill
+    #[arg(long)]
+    backfill_since: Option<DateTime<Utc>>,
+    /// Max pages of 1000 trades fetched per backfill
+    #[arg(long, default_value_t = 10)]
+    backfill_max_pages: usize,
+    /// Trade ids remembered per market and channel, a trade seen again
+    /// within them (after a reconnect or in a backfill) isn't published twice.
+    /// 0 turns it off
+    #[arg(long, default_value_t = 10000)]
+    dedup_window: usize,
+    /// Build per order (level 3) books from the full channel, queryable on
+    /// the metrics address under '/l3/<product>'
+    #[arg(long)]
+    l3: bool,
+    /// REST api used for market discovery and level 3 snapshots
+    #[arg(long, default_value = MAIN_URL)]
+    rest_url: String,
+    /// Keep the last sequence and trade id of each market in this file,
+    /// written periodically and on shutdown and read on startup to report
+    /// the gap and backfill the trades missed in between
+    #[arg(long)]
+    checkpoint_file: Option<PathBuf>,
+    /// Seconds between writes of --checkpoint-file
+    #[arg(long, default_value_t = 10)]
+    checkpoint_interval_secs: u64,
+    /// Seconds to wait on shutdown for the websockets to close and the
+    /// messages still queued to be published
+    #[arg(long, default_value_t = 10)]
+    shutdown_timeout_secs: u64,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Publish the tickers of a recorded feed file to kafka
+    Replay {
+        /// A recording made with --record-dir (.jsonl, .jsonl.gz or .jsonl.zst)
+        #[arg(short, long)]
+        file: PathBuf,
+        /// Kafka broker defaults to 'localhost:9092'
+        #[arg(short, long, default_value = "localhost:9092")]
+        broker: String,
+        /// Kafka topic template, see the feed's --topic
+        #[arg(short, long, default_value = "coinbase-{channel}", value_parser = TopicTemplate::parse)]
+        topic: TopicTemplate,
+        /// Replay speed, '1x' keeps the original timing, '10x' is ten times
+        /// faster and 'max' doesn't wait at all
+        #[arg(short, long, default_value = "1x")]
+        speed: Speed,
+        /// Encoding of kafka records, see the feed's --format
+        #[arg(long, default_value = "json")]
+        format: Format,
+        #[command(flatten)]
+        security: KafkaSecurity,
+    },
 }
 
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
 
-    let args = Args::parse();
-    // TODO args can have multiple markets
-    let market = args.market;
-    let broker = args.broker;
-    let topic = args.topic;
-
-    println!("market stream: {}, broker: {}, topic: {}", market, broker,topic);
-
-    let stream = WSFeed::connect(WS_URL, &[&market], &[ChannelType::Ticker])
-        .await
-        .unwrap();
-
-    stream
-        .for_each(|msg: Result<Message, CBError>| async {
-            match msg.unwrap() {
-                Message::Heartbeat {
-                    sequence,
-                    last_trade_id,
-                    time,
-                    ..
-                } => println!("{}: seq:{} id{}", time, sequence, last_trade_id),
-                Message::Error { message } => println!("Error: {}", message),
-                Message::InternalError(_) => panic!("internal_error"),
-                Message::Ticker(full) => {
-                    let data = serde_json::to_string(&full).unwrap();
-
-                    // produce kafka messaage
-                    match produce_message(data.as_bytes(), &topic, vec![broker.to_owned()]) {
-                        Ok(_) => println!("{:?}", data),
-                        Err(e) => println!("Failed producing messages: {}", e),
-                    }
+    let cli: Vec<OsString> = std::env::args_os().collect();
+    let matches = Args::command().get_matches_from(&cli);
+    let args = layered(&cli, &matches).unwrap_or_else(|e| e.exit());
+
+    let producer_config = ProducerConfig {
+        brokers: vec![args.broker.clone()],
+        batch_size: args.batch_size.max(1),
+        linger: Duration::from_millis(args.linger_ms),
+        // ~ give the brokers one second time to ack the message
+        ack_timeout: Duration::from_secs(1),
+        spool_dir: args.spool_dir.clone(),
+        spool_max_bytes: args.spool_max_mb * 1024 * 1024,
+        security: kafka_security(&args.security),
+    };
+
+    if let Some(Command::Replay {
+        file,
+        broker,
+        topic,
+        speed,
+        format,
+        security,
+    }) = args.command
+    {
+        println!(
+            "replaying {} at {:?}, broker: {}, topic: {}, format: {}",
+            file.display(),
+            speed,
+            broker,
+            topic,
+            format
+        );
+
+        let producer = create_producer(ProducerConfig {
+            brokers: vec![broker],
+            security: kafka_security(&security),
+            ..producer_config
+        });
+        match replay::replay(&file, &producer, &topic, speed, format).await {
+            Ok(count) => println!("replayed {} tickers", count),
+            Err(e) => println!("Failed replaying {}: {}", file.display(), e),
+        }
+
+        producer.close();
+        return;
+    }
+
+    if let Some(path) = &args.config {
+        println!("options from {}", path.display());
+    }
+
+    let filter = ProductFilter {
+        quote: args.quote,
+        pattern: args.market_match,
+    };
+
+    let channel_names: Vec<&str> = args.channel.iter().map(Channel::name).collect();
+    println!(
+        "market streams: {}{}, channels: {}, broker: {}, topic: {}",
+        args.market.join(","),
+        if filter.is_empty() { String::new() } else { format!(" ({})", filter) },
+        channel_names.join(","),
+        args.broker,
+        args.topic
+    );
+
+    let mut sink_kinds = args.sink;
+    if args.record_dir.is_some() && !sink_kinds.contains(&SinkKind::File) {
+        sink_kinds.push(SinkKind::File);
+    }
+
+    let mut publisher = FeedPublisher::builder()
+        .markets(args.market)
+        .discover(filter, Duration::from_secs(args.products_refresh_secs))
+        .exchanges(args.exchange)
+        .channels(args.channel.iter().copied())
+        .topic(args.topic)
+        .normalized_topic(args.normalized_topic)
+        .ws_url(&args.ws_url)
+        .rest_url(&args.rest_url)
+        .binance_url(&args.binance_url)
+        .kraken_url(&args.kraken_url)
+        .backoff(Backoff::new(
+            Duration::from_millis(500),
+            Duration::from_secs(args.max_backoff_secs),
+        ))
+        .dedup_window(args.dedup_window)
+        .shutdown_timeout(Duration::from_secs(args.shutdown_timeout_secs));
+
+    for kind in sink_kinds {
+        let sink: Box<dyn Sink> = match kind {
+            SinkKind::Kafka => {
+                // ~ create the producer once and re-use it for every message.
+                let producer = create_producer(producer_config.clone());
+                if args.spool_dir.is_some() {
+                    log_spool_depth();
                 }
+                Box::new(KafkaSink::new(producer, args.format))
+            }
+            SinkKind::Stdout => Box::new(StdoutSink),
+            SinkKind::File => {
+                let dir = args.record_dir.clone().unwrap_or_else(|| {
+                    let missing = "the file sink needs --record-dir";
+                    Args::command().error(ErrorKind::MissingRequiredArgument, missing).exit()
+                });
+                let segment = Duration::from_secs(args.record_segment_secs.max(1));
+                let recorder = FeedRecorder::new(dir, args.record_compression, segment)
+                    .expect("failed to create feed recorder");
+                Box::new(recorder)
+            }
+            SinkKind::Nats => {
+                let nats = NatsSink::connect(&args.nats_url)
+                    .await
+                    .expect("failed to create nats sink");
+                Box::new(nats)
+            }
+        };
+        publisher = publisher.sink(sink);
+    }
+
+    if args.channel.contains(&Channel::User) {
+        let credentials = match &args.secrets_file {
+            Some(path) => Credentials::from_file(path),
+            None => Credentials::from_env(),
+        };
+        let credentials = credentials.unwrap_or_else(|e| {
+            let missing = format!("the user channel needs api credentials: {}", e);
+            Args::command().error(ErrorKind::MissingRequiredArgument, missing).exit()
+        });
+        publisher = publisher.user(args.user_topic, credentials);
+    }
+    if let Some(book_topic) = args.book_topic {
+        let interval = Duration::from_millis(args.book_interval_ms);
+        publisher = publisher.books(book_topic, args.book_depth, interval);
+    }
+    if let Some(conflate_topic) = args.conflate_topic {
+        let interval = Duration::from_millis(args.conflate_interval_ms);
+        publisher = publisher.conflate(conflate_topic, interval);
+    }
+    if args.backfill || args.backfill_since.is_some() {
+        publisher = publisher.backfill(args.backfill_since, args.backfill_max_pages);
+    }
+    if args.l3 {
+        publisher = publisher.l3();
+    }
+    if let Some(addr) = args.metrics_addr {
+        publisher = publisher.metrics(addr, Duration::from_secs(args.health_timeout_secs));
+    }
+    if let Some(path) = args.checkpoint_file {
+        let interval = Duration::from_secs(args.checkpoint_interval_secs.max(1));
+        publisher = publisher.checkpoint_file(path, interval);
+    }
 
-                other => println!("{:?}", other),
+    let publisher = publisher
+        .build()
+        .unwrap_or_else(|e| Args::command().error(ErrorKind::MissingRequiredArgument, e).exit());
+    let mut handle = publisher.start();
+    let control = handle.control();
+
+    // SIGINT and SIGTERM close the websockets, the queued messages are still
+    // published before the sinks are flushed. SIGHUP reloads the markets.
+    let stop_signal = shutdown::signalled();
+    tokio::pin!(stop_signal);
+    let mut hangup = signal(SignalKind::hangup()).expect("failed to listen for SIGHUP");
+    let mut stopping = false;
+    let result = loop {
+        tokio::select! {
+            result = handle.join() => break result,
+            signal = &mut stop_signal, if !stopping => {
+                println!("{} received, shutting down", signal);
+                stopping = true;
+                control.stop();
             }
-        })
-        .await;
+            Some(()) = hangup.recv() => reload(&cli, &matches, &control),
+        }
+    };
+
+    let dropped = metrics::DROPPED.get();
+    if dropped > 0 {
+        println!("{} records were dropped", dropped);
+    }
+
+    if let Err(e) = result {
+        println!("shut down with errors: {}", e);
+        std::process::exit(1);
+    }
+    println!("shut down cleanly");
 }
 
-fn produce_message<'a, 'b>(
-    data: &'a [u8],
-    topic: &'b str,
-    brokers: Vec<String>,
-) -> Result<(), KafkaError> {
-    // ~ create a producer. this is a relatively costly operation, so
-    // you'll do this typically once in your application and re-use
-    // the instance many times.
-    let mut producer = Producer::from_hosts(brokers)
-        // ~ give the brokers one second time to ack the message
-        .with_ack_timeout(Duration::from_secs(1))
-        // ~ require only one broker to ack the message
-        .with_required_acks(RequiredAcks::One)
-        // ~ build the producer with the above settings
-        .create()?;
-
-    // ~ now send a single message.  this is a synchronous/blocking
-    // operation.
-
-    // ~ we're sending 'data' as a 'value'. there will be no key
-    // associated with the sent message.
-
-    // ~ we leave the partition "unspecified" - this is a negative
-    // partition - which causes the producer to find out one on its
-    // own using its underlying partitioner.
-    producer.send(&Record {
-        topic,
-        partition: -1,
-        key: (),
-        value: data,
-    })?;
+/// The arguments of the command line layered over the COINBASE_* variables
+/// and the --config file
+fn layered(cli: &[OsString], matches: &ArgMatches) -> Result<Args, clap::Error> {
+    if matches.subcommand().is_some() {
+        return Args::from_arg_matches(matches);
+    }
+    let invalid = |e: String| Args::command().error(ErrorKind::InvalidValue, e);
+
+    let file = match matches.get_one::<PathBuf>("config") {
+        Some(path) => config::load(path).map_err(invalid)?,
+        None => config::ConfigFile::new(),
+    };
+    let layers = config::layer(&Args::command(), matches, &file).map_err(invalid)?;
 
-    // ~ we can achieve exactly the same as above in a shorter way with
-    // the following call
-    producer.send(&Record::from_value(topic, data))?;
+    let mut argv = cli[..1].to_vec();
+    argv.extend(layers.into_iter().map(OsString::from));
+    argv.extend_from_slice(&cli[1..]);
+    Args::try_parse_from(argv)
+}
+
+/// Re-reads the markets on SIGHUP, the other options only apply on restart
+fn reload(cli: &[OsString], matches: &ArgMatches, control: &FeedControl) {
+    if matches.value_source("market") == Some(ValueSource::CommandLine) {
+        println!("SIGHUP received, the markets are set on the command line, keeping them");
+        return;
+    }
+    match layered(cli, matches) {
+        Ok(args) => {
+            println!("SIGHUP received, markets: {}", args.market.join(","));
+            control.set_markets(args.market);
+        }
+        Err(e) => println!("SIGHUP received, keeping the markets: {}", e),
+    }
+}
+
+/// The kafka security options, exits like clap on an invalid combination
+fn kafka_security(options: &KafkaSecurity) -> SecurityConfig {
+    options
+        .security()
+        .unwrap_or_else(|e| Args::command().error(ErrorKind::InvalidValue, e).exit())
+}
+
+fn create_producer(config: ProducerConfig) -> FeedProducer {
+    // bad certificates are a config error, reported like the consumer does
+    FeedProducer::create(config).unwrap_or_else(|e| {
+        println!("Failed creating the kafka producer: {}", e);
+        std::process::exit(1);
+    })
+}
 
-    Ok(())
+/// Logs the spool depth every minute while records are spooled or dropped
+fn log_spool_depth() {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let depth = metrics::SPOOL_DEPTH.get();
+            let dropped = metrics::DROPPED.get();
+            if depth > 0 || dropped > 0 {
+                println!("spool depth: {}, dropped: {}", depth, dropped);
+            }
+        }
+    });
 }
@@ -0,0 +1,183 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+
+use chrono::Utc;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, Encoder, Histogram, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use tokio::sync::watch;
+
+use crate::l3book::L3Handle;
+use crate::shutdown;
+
+lazy_static! {
+    pub static ref MESSAGES_RECEIVED: IntCounterVec = register_int_counter_vec!(
+        "coinbase_messages_received_total",
+        "Websocket messages received",
+        &["product", "channel"]
+    )
+    .unwrap();
+    pub static ref PRODUCED: IntCounter = register_int_counter!(
+        "coinbase_produce_success_total",
+        "Records acked by kafka"
+    )
+    .unwrap();
+    pub static ref PRODUCE_FAILURES: IntCounter = register_int_counter!(
+        "coinbase_produce_failures_total",
+        "Records kafka failed to take"
+    )
+    .unwrap();
+    pub static ref PRODUCE_LATENCY: Histogram = register_histogram!(
+        "coinbase_produce_latency_seconds",
+        "Time kafka took to ack a batch",
+        vec![0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5]
+    )
+    .unwrap();
+    pub static ref SPOOL_DEPTH: IntGauge = register_int_gauge!(
+        "coinbase_spool_depth",
+        "Records waiting in the spool"
+    )
+    .unwrap();
+    pub static ref DROPPED: IntCounter = register_int_counter!(
+        "coinbase_dropped_total",
+        "Records dropped because kafka failed and the spool was full or disabled"
+    )
+    .unwrap();
+    pub static ref RECONNECTS: IntCounter = register_int_counter!(
+        "coinbase_websocket_reconnects_total",
+        "Websocket reconnects"
+    )
+    .unwrap();
+    pub static ref BACKFILLED: IntCounter = register_int_counter!(
+        "coinbase_backfilled_trades_total",
+        "Missed trades recovered from the REST api"
+    )
+    .unwrap();
+    pub static ref DUPLICATE_TRADES: IntCounter = register_int_counter!(
+        "coinbase_duplicate_trades_total",
+        "Trades not published again because their trade id was seen before"
+    )
+    .unwrap();
+    pub static ref SUBSCRIBED_PRODUCTS: IntGauge = register_int_gauge!(
+        "coinbase_subscribed_products",
+        "Markets the websocket feed is subscribed to"
+    )
+    .unwrap();
+    pub static ref LAST_SEQUENCE: IntGaugeVec = register_int_gauge_vec!(
+        "coinbase_last_sequence",
+        "Last exchange sequence number seen",
+        &["product"]
+    )
+    .unwrap();
+    pub static ref WEBSOCKET_LAG: HistogramVec = register_histogram_vec!(
+        "coinbase_websocket_lag_seconds",
+        "Time between the exchange timestamp of a message and receiving it",
+        &["product"],
+        vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]
+    )
+    .unwrap();
+}
+
+/// Unix time of the last message received, starts at process start so the
+/// health check gives the feed a chance to connect
+static LAST_MESSAGE: AtomicI64 = AtomicI64::new(0);
+
+/// Marks that a message just arrived
+pub fn touch() {
+    LAST_MESSAGE.store(Utc::now().timestamp(), Ordering::Relaxed);
+}
+
+/// Price levels per side returned by `/l3/<product>`
+const L3_DEPTH: usize = 20;
+
+/// Serves `/metrics` and `/healthz` on `addr`. The health check fails when no
+/// message arrived for `stale_after`. With level 3 books it also answers
+/// `/l3/<product>` and `/l3/<product>/orders/<order id>`. Stops once
+/// `shutdown` is set.
+pub async fn serve(
+    addr: SocketAddr,
+    stale_after: Duration,
+    l3: Option<L3Handle>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    touch();
+
+    let make_svc = make_service_fn(move |_| {
+        let l3 = l3.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| handle(req, stale_after, l3.clone())))
+        }
+    });
+
+    println!("serving metrics on http://{}/metrics", addr);
+    let server = Server::bind(&addr)
+        .serve(make_svc)
+        .with_graceful_shutdown(async move { shutdown::stopped(&mut shutdown).await });
+    if let Err(e) = server.await {
+        println!("Metrics server error: {}", e);
+    }
+}
+
+async fn handle(
+    req: Request<Body>,
+    stale_after: Duration,
+    l3: Option<L3Handle>,
+) -> Result<Response<Body>, Infallible> {
+    let segments: Vec<&str> = req.uri().path().trim_matches('/').split('/').collect();
+
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            let encoder = TextEncoder::new();
+            let mut buffer = Vec::new();
+            match encoder.encode(&prometheus::gather(), &mut buffer) {
+                Ok(_) => Response::builder()
+                    .header("Content-Type", encoder.format_type())
+                    .body(Body::from(buffer)),
+                Err(e) => Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::from(e.to_string())),
+            }
+        }
+        (&Method::GET, "/healthz") => {
+            let silent = Utc::now().timestamp() - LAST_MESSAGE.load(Ordering::Relaxed);
+            if silent <= stale_after.as_secs() as i64 {
+                Response::builder().body(Body::from("ok"))
+            } else {
+                Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(Body::from(format!("no message for {}s", silent)))
+            }
+        }
+        (&Method::GET, _) if segments[0] == "l3" && l3.is_some() => {
+            let l3 = l3.as_ref().unwrap();
+            let found = match segments[1..] {
+                [product_id] => l3
+                    .summary(product_id, L3_DEPTH)
+                    .map(|summary| serde_json::to_string(&summary).unwrap()),
+                [product_id, "orders", order_id] => l3
+                    .queue_position(product_id, order_id)
+                    .map(|position| serde_json::to_string(&position).unwrap()),
+                _ => None,
+            };
+            match found {
+                Some(json) => Response::builder()
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(json)),
+                None => Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty()),
+            }
+        }
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+
+    Ok(response.unwrap())
+}
@@ -0,0 +1,360 @@
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rdkafka::error::KafkaError;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::ClientConfig;
+
+use crate::metrics;
+use crate::security::SecurityConfig;
+use crate::spool::{Spool, SpooledRecord};
+
+/// How often spooled records are retried while kafka is failing
+const SPOOL_RETRY: Duration = Duration::from_secs(1);
+
+/// Settings for the long-lived kafka producer
+#[derive(Debug, Clone)]
+pub struct ProducerConfig {
+    pub brokers: Vec<String>,
+    /// max number of records sent to the brokers in one request
+    pub batch_size: usize,
+    /// how long to wait for more records before sending a partial batch
+    pub linger: Duration,
+    /// how long a record may take to be acked, librdkafka retries within it
+    pub ack_timeout: Duration,
+    /// where to spool records while kafka is unavailable, they're dropped
+    /// when not set
+    pub spool_dir: Option<PathBuf>,
+    /// max bytes kept in the spool before new records are dropped
+    pub spool_max_bytes: u64,
+    /// TLS and SASL, plaintext by default
+    pub security: SecurityConfig,
+}
+
+/// A single kafka producer shared by the whole feed loop.
+///
+/// Waiting for acks blocks, so the producer lives on its own thread and the
+/// feed hands records over a channel. The thread batches records until either
+/// `batch_size` is reached or `linger` expires, hands them to librdkafka and
+/// waits until every one is acked. Batches kafka doesn't take go to the
+/// spool, and while the spool holds anything new records queue up behind it
+/// so they stay in order. librdkafka connects in the background, so records
+/// are spooled (or dropped without a spool) while kafka is down at startup
+/// just like later on.
+pub struct FeedProducer {
+    tx: Option<Sender<SpooledRecord>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl FeedProducer {
+    /// Opens the spool and starts the producer thread
+    pub fn create(config: ProducerConfig) -> Result<Self, String> {
+        // bad certificates are a config error, an unreachable broker isn't
+        config.security.check()?;
+        let producer = build_producer(&config)?;
+
+        let spool = match &config.spool_dir {
+            Some(dir) => {
+                let spool = Spool::open(dir, config.spool_max_bytes).map_err(|e| e.to_string())?;
+                Some(spool)
+            }
+            None => None,
+        };
+
+        if let Some(spool) = &spool {
+            metrics::SPOOL_DEPTH.set(spool.depth() as i64);
+        }
+
+        let (tx, rx) = mpsc::channel::<SpooledRecord>();
+
+        let worker = Worker {
+            producer,
+            last_failure: None,
+            spool,
+            batch_size: config.batch_size,
+            linger: config.linger,
+        };
+        let handle = thread::spawn(move || worker.run(rx));
+
+        Ok(Self {
+            tx: Some(tx),
+            handle: Some(handle),
+        })
+    }
+
+    /// Queues a record for the producer thread. This never blocks the feed.
+    pub fn send(&self, topic: &str, key: &str, value: Vec<u8>) -> Result<(), String> {
+        let record = SpooledRecord {
+            topic: topic.to_owned(),
+            key: key.to_owned(),
+            value,
+        };
+
+        match &self.tx {
+            Some(tx) => tx
+                .send(record)
+                .map_err(|_| "kafka producer thread has stopped".to_string()),
+            None => Err("kafka producer is closed".to_string()),
+        }
+    }
+
+    /// Flushes any pending records and waits for the producer thread to exit
+    pub fn close(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        // dropping the sender lets the thread drain the channel and exit
+        self.tx.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for FeedProducer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// The producer thread
+struct Worker {
+    producer: FutureProducer,
+    /// when kafka last failed to take a batch
+    last_failure: Option<Instant>,
+    spool: Option<Spool>,
+    batch_size: usize,
+    linger: Duration,
+}
+
+impl Worker {
+    fn run(mut self, rx: Receiver<SpooledRecord>) {
+        let mut batch: Vec<SpooledRecord> = Vec::with_capacity(self.batch_size);
+
+        loop {
+            // block until the first record of a batch arrives, waking up
+            // now and then to retry the spool
+            let first = if self.spooled() == 0 {
+                rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            } else {
+                rx.recv_timeout(SPOOL_RETRY)
+            };
+
+            let mut disconnected = false;
+            match first {
+                Ok(record) => batch.push(record),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => disconnected = true,
+            }
+
+            let deadline = Instant::now() + self.linger;
+            while !batch.is_empty() && !disconnected && batch.len() < self.batch_size {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                match rx.recv_timeout(remaining) {
+                    Ok(record) => batch.push(record),
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => disconnected = true,
+                }
+            }
+
+            // spooled records are older, they go first
+            self.drain_spool();
+
+            if !batch.is_empty() {
+                if self.spooled() > 0 {
+                    self.spool_batch(&batch);
+                } else if let Err(e) = send_batch(&self.producer, &batch) {
+                    println!("Failed producing {} messages: {}", batch.len(), e);
+                    self.last_failure = Some(Instant::now());
+                    self.spool_batch(&batch);
+                }
+                batch.clear();
+            }
+
+            if disconnected {
+                // anything still spooled is sent on the next run
+                if self.spooled() > 0 {
+                    println!("leaving {} records in the spool", self.spooled());
+                }
+                break;
+            }
+        }
+    }
+
+    fn spooled(&self) -> usize {
+        self.spool.as_ref().map(Spool::depth).unwrap_or(0)
+    }
+
+    /// Sends spooled records in order until the spool is empty or kafka
+    /// fails again. Tries at most once every SPOOL_RETRY after a failure, as
+    /// every try waits up to the ack timeout while kafka is down.
+    fn drain_spool(&mut self) {
+        let retry = match self.last_failure {
+            Some(last) => last.elapsed() >= SPOOL_RETRY,
+            None => true,
+        };
+        let spool = match &mut self.spool {
+            Some(spool) if !spool.is_empty() && retry => spool,
+            _ => return,
+        };
+
+        while !spool.is_empty() {
+            let records = match spool.peek(self.batch_size) {
+                Ok(records) => records,
+                Err(e) => {
+                    println!("Failed reading the spool: {}", e);
+                    return;
+                }
+            };
+            let end = match records.last() {
+                Some((_, end)) => *end,
+                None => return,
+            };
+
+            let batch: Vec<SpooledRecord> = records.into_iter().map(|(r, _)| r).collect();
+            if send_batch(&self.producer, &batch).is_err() {
+                // kafka is still down, try again later
+                self.last_failure = Some(Instant::now());
+                return;
+            }
+
+            if let Err(e) = spool.advance(end, batch.len()) {
+                println!("Failed updating the spool: {}", e);
+                return;
+            }
+            metrics::SPOOL_DEPTH.set(spool.depth() as i64);
+
+            if spool.is_empty() {
+                println!("spool drained");
+            }
+        }
+    }
+
+    /// Keeps a batch kafka didn't take, dropping it when there's no room
+    fn spool_batch(&mut self, batch: &[SpooledRecord]) {
+        let spool = match &mut self.spool {
+            Some(spool) => spool,
+            None => {
+                metrics::DROPPED.inc_by(batch.len() as u64);
+                return;
+            }
+        };
+
+        let mut dropped = 0;
+        for record in batch {
+            match spool.push(record) {
+                Ok(true) => {}
+                Ok(false) => dropped += 1,
+                Err(e) => {
+                    println!("Failed spooling record: {}", e);
+                    dropped += 1;
+                }
+            }
+        }
+
+        if let Err(e) = spool.sync() {
+            println!("Failed syncing the spool: {}", e);
+        }
+
+        if dropped > 0 {
+            println!("spool is full, dropped {} records", dropped);
+            metrics::DROPPED.inc_by(dropped as u64);
+        }
+        println!("spooled {} records, spool depth: {}", batch.len() - dropped, spool.depth());
+        metrics::SPOOL_DEPTH.set(spool.depth() as i64);
+    }
+}
+
+fn build_producer(config: &ProducerConfig) -> Result<FutureProducer, String> {
+    let mut client = ClientConfig::new();
+    client
+        .set("bootstrap.servers", config.brokers.join(","))
+        .set("acks", "1")
+        .set("message.timeout.ms", config.ack_timeout.as_millis().to_string())
+        // the worker batches, librdkafka sends what it's handed right away
+        .set("linger.ms", "0");
+    config.security.apply(&mut client);
+    client
+        .create()
+        .map_err(|e| format!("invalid kafka config: {}", e))
+}
+
+fn send_batch(producer: &FutureProducer, batch: &[SpooledRecord]) -> Result<(), KafkaError> {
+    let timer = metrics::PRODUCE_LATENCY.start_timer();
+    let result = send_all(producer, batch);
+    timer.observe_duration();
+
+    match &result {
+        Ok(_) => metrics::PRODUCED.inc_by(batch.len() as u64),
+        Err(_) => metrics::PRODUCE_FAILURES.inc_by(batch.len() as u64),
+    }
+    result
+}
+
+fn send_all(producer: &FutureProducer, batch: &[SpooledRecord]) -> Result<(), KafkaError> {
+    // ~ the product id is the record key so the partitioner keeps each
+    // market on a consistent partition.
+    let mut deliveries = Vec::with_capacity(batch.len());
+    let mut failed = None;
+    for r in batch {
+        let record = FutureRecord::to(&r.topic)
+            .key(r.key.as_bytes())
+            .payload(r.value.as_slice());
+        match producer.send_result(record) {
+            Ok(delivery) => deliveries.push(delivery),
+            Err((e, _)) => {
+                failed = Some(e);
+                break;
+            }
+        }
+    }
+
+    // ~ every record of the batch is waited for. a partition can still
+    // reject its part of the batch, the whole batch is then retried so some
+    // records may be delivered twice.
+    for delivery in deliveries {
+        let result = match futures::executor::block_on(delivery) {
+            Ok(Ok(_)) => continue,
+            Ok(Err((e, _))) => e,
+            Err(_) => KafkaError::Canceled,
+        };
+        failed.get_or_insert(result);
+    }
+
+    match failed {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spools_while_kafka_is_down_at_startup() {
+        let dir = std::env::temp_dir().join(format!("coinbase-producer-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let producer = FeedProducer::create(ProducerConfig {
+            // nothing listens on the discard port
+            brokers: vec!["127.0.0.1:9".to_string()],
+            batch_size: 10,
+            linger: Duration::from_millis(1),
+            ack_timeout: Duration::from_secs(1),
+            spool_dir: Some(dir.clone()),
+            spool_max_bytes: 1024 * 1024,
+            security: SecurityConfig::default(),
+        })
+        .unwrap();
+        producer.send("coinbase-ticker", "BTC-USD", b"{}".to_vec()).unwrap();
+        producer.close();
+
+        let spool = Spool::open(&dir, 1024 * 1024).unwrap();
+        assert_eq!(spool.depth(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
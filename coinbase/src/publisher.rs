@@ -0,0 +1,993 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use coinbase_pro_rs::structs::wsfeed::{Full, Message};
+use coinbase_pro_rs::{MAIN_URL, WS_URL};
+use feed_schema::{Normalized, Trade};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+use crate::backfill::{Backfill, BackfilledTrade, RestTrade, Since};
+use crate::book::OrderBooks;
+use crate::checkpoint::Checkpoint;
+use crate::conflate::Conflation;
+use crate::dedup::TradeIds;
+use crate::discovery::{Discovery, ProductFilter};
+use crate::exchange::{
+    self, Binance, Coinbase, ExchangeAdapter, ExchangeKind, ExchangeMarket, Kraken, BINANCE_URL,
+    KRAKEN_URL,
+};
+use crate::feed::{
+    self, message_channel, message_payload, message_product_id, message_sequence, message_time,
+    message_trade_id, Backoff, Channel, FeedEvent, SequenceGap,
+};
+use crate::l3book::{self, RestSnapshots};
+use crate::metrics;
+use crate::shutdown;
+use crate::sink::{Sink, SinkRecord};
+use crate::topic::TopicTemplate;
+use crate::user::Credentials;
+
+/// Publishes the coinbase feed, and normalized trades and quotes of other
+/// exchanges, to a set of sinks. Built with [`FeedPublisher::builder`], it
+/// runs on a task of its own once started, e.g. to embed the feed in another
+/// service with a [`ChannelSink`](crate::sink::ChannelSink).
+pub struct FeedPublisher {
+    config: Config,
+    sinks: Vec<Box<dyn Sink>>,
+}
+
+/// Everything the builder collects
+struct Config {
+    markets: Vec<String>,
+    filter: ProductFilter,
+    products_refresh: Duration,
+    exchanges: Vec<ExchangeMarket>,
+    channels: Vec<Channel>,
+    topic: TopicTemplate,
+    normalized_topic: TopicTemplate,
+    user_topic: TopicTemplate,
+    credentials: Option<Credentials>,
+    ws_url: String,
+    binance_url: String,
+    kraken_url: String,
+    rest_url: String,
+    backoff: Backoff,
+    book_topic: Option<TopicTemplate>,
+    book_depth: usize,
+    book_interval: Duration,
+    conflate_topic: Option<TopicTemplate>,
+    conflate_interval: Duration,
+    backfill: bool,
+    backfill_since: Option<DateTime<Utc>>,
+    backfill_max_pages: usize,
+    dedup_window: usize,
+    l3: bool,
+    metrics_addr: Option<SocketAddr>,
+    health_timeout: Duration,
+    checkpoint_file: Option<PathBuf>,
+    checkpoint_interval: Duration,
+    shutdown_timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let template = |template: &str| TopicTemplate::parse(template).unwrap();
+        Self {
+            markets: Vec::new(),
+            filter: ProductFilter::default(),
+            products_refresh: Duration::from_secs(300),
+            exchanges: Vec::new(),
+            channels: vec![Channel::Ticker],
+            topic: template("coinbase-{channel}"),
+            normalized_topic: template("normalized-{channel}"),
+            user_topic: template("coinbase-user-{product}"),
+            credentials: None,
+            ws_url: WS_URL.to_owned(),
+            binance_url: BINANCE_URL.to_owned(),
+            kraken_url: KRAKEN_URL.to_owned(),
+            rest_url: MAIN_URL.to_owned(),
+            backoff: Backoff::new(Duration::from_millis(500), Duration::from_secs(60)),
+            book_topic: None,
+            book_depth: 10,
+            book_interval: Duration::from_secs(1),
+            conflate_topic: None,
+            conflate_interval: Duration::from_secs(1),
+            backfill: false,
+            backfill_since: None,
+            backfill_max_pages: 10,
+            dedup_window: 10_000,
+            l3: false,
+            metrics_addr: None,
+            health_timeout: Duration::from_secs(30),
+            checkpoint_file: None,
+            checkpoint_interval: Duration::from_secs(10),
+            shutdown_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Configures a [`FeedPublisher`], every setting but the markets and the
+/// sinks has a default
+#[derive(Default)]
+pub struct FeedPublisherBuilder {
+    config: Config,
+    sinks: Vec<Box<dyn Sink>>,
+}
+
+impl FeedPublisherBuilder {
+    /// Coinbase markets to subscribe to e.g. 'BTC-USD'
+    pub fn markets<I, S>(mut self, markets: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.config.markets = markets.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Also subscribe to the online markets matching `filter`, refreshed
+    /// every `refresh`
+    pub fn discover(mut self, filter: ProductFilter, refresh: Duration) -> Self {
+        self.config.filter = filter;
+        self.config.products_refresh = refresh.max(Duration::from_secs(1));
+        self
+    }
+
+    /// Markets on other exchanges to publish normalized trades and quotes of
+    pub fn exchanges(mut self, exchanges: Vec<ExchangeMarket>) -> Self {
+        self.config.exchanges = exchanges;
+        self
+    }
+
+    /// Websocket channels to subscribe to and publish, 'ticker' by default
+    pub fn channels(mut self, channels: impl IntoIterator<Item = Channel>) -> Self {
+        self.config.channels = channels.into_iter().collect();
+        self
+    }
+
+    /// Topic template of the coinbase feed, 'coinbase-{channel}' by default
+    pub fn topic(mut self, topic: TopicTemplate) -> Self {
+        self.config.topic = topic;
+        self
+    }
+
+    /// Topic template of the normalized trades and quotes
+    pub fn normalized_topic(mut self, topic: TopicTemplate) -> Self {
+        self.config.normalized_topic = topic;
+        self
+    }
+
+    /// Topic template of the `user` channel and the credentials it's signed with
+    pub fn user(mut self, topic: TopicTemplate, credentials: Credentials) -> Self {
+        self.config.user_topic = topic;
+        self.config.credentials = Some(credentials);
+        self
+    }
+
+    /// Coinbase websocket feed
+    pub fn ws_url(mut self, url: &str) -> Self {
+        self.config.ws_url = url.to_owned();
+        self
+    }
+
+    /// Coinbase REST api for discovery, backfills and level 3 snapshots
+    pub fn rest_url(mut self, url: &str) -> Self {
+        self.config.rest_url = url.to_owned();
+        self
+    }
+
+    pub fn binance_url(mut self, url: &str) -> Self {
+        self.config.binance_url = url.to_owned();
+        self
+    }
+
+    pub fn kraken_url(mut self, url: &str) -> Self {
+        self.config.kraken_url = url.to_owned();
+        self
+    }
+
+    /// How long to wait between websocket reconnects
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.config.backoff = backoff;
+        self
+    }
+
+    /// Adds a sink every record is published to
+    pub fn sink(mut self, sink: Box<dyn Sink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Publishes the top `depth` levels of every level2 book to `topic`
+    /// every `interval`
+    pub fn books(mut self, topic: TopicTemplate, depth: usize, interval: Duration) -> Self {
+        self.config.book_topic = Some(topic);
+        self.config.book_depth = depth;
+        self.config.book_interval = interval.max(Duration::from_millis(1));
+        self
+    }
+
+    /// Also publishes at most one ticker per product every `interval` to
+    /// `topic`, the latest one with the size traded since the previous
+    pub fn conflate(mut self, topic: TopicTemplate, interval: Duration) -> Self {
+        self.config.conflate_topic = Some(topic);
+        self.config.conflate_interval = interval.max(Duration::from_millis(1));
+        self
+    }
+
+    /// Backfills the trades missed across reconnects, and since `since` on
+    /// startup, fetching at most `max_pages` pages each time
+    pub fn backfill(mut self, since: Option<DateTime<Utc>>, max_pages: usize) -> Self {
+        self.config.backfill = true;
+        self.config.backfill_since = since;
+        self.config.backfill_max_pages = max_pages.max(1);
+        self
+    }
+
+    /// Trade ids remembered per market and channel to drop trades published
+    /// before, 10000 by default and 0 to publish duplicates
+    pub fn dedup_window(mut self, window: usize) -> Self {
+        self.config.dedup_window = window;
+        self
+    }
+
+    /// Builds level 3 books from the full channel, served on the metrics address
+    pub fn l3(mut self) -> Self {
+        self.config.l3 = true;
+        self
+    }
+
+    /// Serves prometheus metrics and the health check on `addr`
+    pub fn metrics(mut self, addr: SocketAddr, health_timeout: Duration) -> Self {
+        self.config.metrics_addr = Some(addr);
+        self.config.health_timeout = health_timeout;
+        self
+    }
+
+    /// Resumes from and writes the last sequence and trade per market to
+    /// `path`, every `interval` and on shutdown
+    pub fn checkpoint_file(mut self, path: PathBuf, interval: Duration) -> Self {
+        self.config.checkpoint_file = Some(path);
+        self.config.checkpoint_interval = interval.max(Duration::from_millis(1));
+        self
+    }
+
+    /// How long a stop waits for the websockets to close
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.config.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Checks the settings fit together
+    pub fn build(self) -> Result<FeedPublisher, String> {
+        let config = &self.config;
+        if config.markets.is_empty() && config.filter.is_empty() && config.exchanges.is_empty() {
+            return Err("no markets to subscribe to".to_string());
+        }
+        if self.sinks.is_empty() {
+            return Err("no sinks to publish to".to_string());
+        }
+        if config.channels.contains(&Channel::User) && config.credentials.is_none() {
+            return Err("the user channel needs api credentials".to_string());
+        }
+
+        Ok(FeedPublisher {
+            config: self.config,
+            sinks: self.sinks,
+        })
+    }
+}
+
+/// A running [`FeedPublisher`]
+pub struct FeedHandle {
+    control: FeedControl,
+    task: JoinHandle<Result<(), String>>,
+}
+
+/// Steers a running [`FeedPublisher`] from anywhere, cheap to clone
+#[derive(Clone)]
+pub struct FeedControl {
+    stop: Arc<watch::Sender<bool>>,
+    markets: Arc<watch::Sender<Vec<String>>>,
+}
+
+impl FeedControl {
+    /// Closes the websockets, the messages already received are still
+    /// published before the sinks are flushed
+    pub fn stop(&self) {
+        let _ = self.stop.send(true);
+    }
+
+    /// Replaces the coinbase markets. Only the added and dropped ones are
+    /// (un)subscribed, the others keep streaming on their connection.
+    pub fn set_markets(&self, markets: Vec<String>) {
+        self.markets.send_if_modified(|current| {
+            if *current == markets {
+                return false;
+            }
+            *current = markets;
+            true
+        });
+    }
+}
+
+impl FeedHandle {
+    pub fn control(&self) -> FeedControl {
+        self.control.clone()
+    }
+
+    /// See [`FeedControl::stop`]
+    pub fn stop(&self) {
+        self.control.stop();
+    }
+
+    /// See [`FeedControl::set_markets`]
+    pub fn set_markets(&self, markets: Vec<String>) {
+        self.control.set_markets(markets);
+    }
+
+    /// Waits for the publisher to stop, an error means records or the
+    /// checkpoint may not have been written
+    pub async fn join(&mut self) -> Result<(), String> {
+        (&mut self.task).await.map_err(|e| e.to_string())?
+    }
+}
+
+impl FeedPublisher {
+    pub fn builder() -> FeedPublisherBuilder {
+        FeedPublisherBuilder::default()
+    }
+
+    /// Connects and publishes on a task of its own until stopped
+    pub fn start(self) -> FeedHandle {
+        let (stop, shutdown) = watch::channel(false);
+        let (markets, markets_rx) = watch::channel(self.config.markets.clone());
+        let task = tokio::spawn(self.run(shutdown, markets_rx));
+        let control = FeedControl {
+            stop: Arc::new(stop),
+            markets: Arc::new(markets),
+        };
+        FeedHandle { control, task }
+    }
+
+    async fn run(
+        self,
+        shutdown: watch::Receiver<bool>,
+        mut markets: watch::Receiver<Vec<String>>,
+    ) -> Result<(), String> {
+        let FeedPublisher { config, mut sinks } = self;
+        let channels = &config.channels;
+        let topic = &config.topic;
+
+        // tasks next to the feed, they stop on shutdown and are joined
+        // before the sinks are closed
+        let mut tasks: Vec<JoinHandle<()>> = Vec::new();
+
+        let l3 = config.l3.then(|| {
+            let source = RestSnapshots::new(&config.rest_url);
+            let (l3, task) = l3book::spawn(source, shutdown.clone());
+            tasks.push(task);
+            l3
+        });
+
+        if let Some(addr) = config.metrics_addr {
+            let server = metrics::serve(addr, config.health_timeout, l3.clone(), shutdown.clone());
+            tasks.push(tokio::spawn(server));
+        }
+
+        // books need their channels even when they aren't published
+        let mut subscriptions = channels.clone();
+        let mut books = config.book_topic.as_ref().map(|_| OrderBooks::default());
+        if books.is_some() && !subscriptions.contains(&Channel::Level2) {
+            subscriptions.push(Channel::Level2);
+        }
+        if l3.is_some() && !subscriptions.contains(&Channel::Full) {
+            subscriptions.push(Channel::Full);
+        }
+        let mut conflation = config.conflate_topic.as_ref().map(|_| Conflation::default());
+        if conflation.is_some() && !subscriptions.contains(&Channel::Ticker) {
+            subscriptions.push(Channel::Ticker);
+        }
+
+        // discovered markets are refreshed from the REST api and the status channel
+        // the markets set by hand can change while running
+        let discovery = (!config.filter.is_empty()).then(|| {
+            let filter = config.filter.clone();
+            Arc::new(Discovery::new(&config.rest_url, filter, config.markets.clone()))
+        });
+        let products = match &discovery {
+            Some(discovery) => {
+                if !subscriptions.contains(&Channel::Status) {
+                    subscriptions.push(Channel::Status);
+                }
+                let refresh = config.products_refresh;
+                let runner = discovery.clone();
+                let stopping = shutdown.clone();
+                tasks.push(tokio::spawn(async move { runner.run(refresh, stopping).await }));
+
+                let pinned = discovery.clone();
+                let mut stopping = shutdown.clone();
+                tasks.push(tokio::spawn(async move {
+                    loop {
+                        tokio::select! {
+                            changed = markets.changed() => if changed.is_err() {
+                                break;
+                            },
+                            _ = shutdown::stopped(&mut stopping) => break,
+                        }
+                        let pinned_markets = markets.borrow_and_update().clone();
+                        pinned.set_pinned(pinned_markets);
+                    }
+                }));
+                discovery.subscribe()
+            }
+            None => {
+                let products = markets.clone();
+                let mut stopping = shutdown.clone();
+                tasks.push(tokio::spawn(async move {
+                    loop {
+                        let count = markets.borrow_and_update().len();
+                        metrics::SUBSCRIBED_PRODUCTS.set(count as i64);
+                        tokio::select! {
+                            changed = markets.changed() => if changed.is_err() {
+                                break;
+                            },
+                            _ = shutdown::stopped(&mut stopping) => break,
+                        }
+                    }
+                }));
+                products
+            }
+        };
+
+        let backfill = (config.backfill || config.backfill_since.is_some())
+            .then(|| Backfill::new(&config.rest_url, config.backfill_max_pages));
+        // last trade published per product, and products that need a backfill
+        // before their next trade
+        let mut last_trades: HashMap<String, u64> = HashMap::new();
+        let mut pending_backfill: HashSet<String> = HashSet::new();
+        // trades already published, they come again after reconnects and
+        // can overlap with backfills
+        let mut trade_ids: TradeIds<u64> = TradeIds::new(config.dedup_window);
+        let mut normalized_ids: TradeIds<String> = TradeIds::new(config.dedup_window);
+
+        let mut checkpoint = match &config.checkpoint_file {
+            Some(path) => Checkpoint::load(path)?,
+            None => Checkpoint::default(),
+        };
+        // sequences the previous run stopped at, checked against the first
+        // message of each market
+        let mut resumed: HashMap<String, u64> = HashMap::new();
+        for (product_id, last) in &checkpoint.products {
+            if let Some(sequence) = last.sequence {
+                resumed.insert(product_id.clone(), sequence);
+            }
+            if let (Some(_), Some(trade_id)) = (&backfill, last.trade_id) {
+                last_trades.insert(product_id.clone(), trade_id);
+                pending_backfill.insert(product_id.clone());
+            }
+        }
+        if !checkpoint.products.is_empty() {
+            println!("resuming {} markets from the checkpoint", checkpoint.products.len());
+        }
+
+        // the books of dropped markets are removed
+        let mut subscribed = products.clone();
+        let mut current: HashSet<String> = subscribed.borrow_and_update().iter().cloned().collect();
+        let mut following = books.is_some() || l3.is_some();
+
+        // the coinbase feed waits while there are no markets
+        let (tx, mut events) = mpsc::unbounded_channel();
+        feed::spawn(
+            &config.ws_url,
+            products,
+            &subscriptions,
+            config.credentials.clone(),
+            config.backoff.clone(),
+            tx.clone(),
+            shutdown.clone(),
+        );
+
+        // the markets of each exchange share one connection
+        let mut exchange_markets: HashMap<ExchangeKind, Vec<String>> = HashMap::new();
+        for market in &config.exchanges {
+            exchange_markets.entry(market.exchange).or_default().push(market.symbol.clone());
+        }
+        for (kind, symbols) in exchange_markets {
+            let adapter: Arc<dyn ExchangeAdapter> = match kind {
+                ExchangeKind::Coinbase => Arc::new(Coinbase::new(&config.ws_url)),
+                ExchangeKind::Binance => Arc::new(Binance::new(&config.binance_url)),
+                ExchangeKind::Kraken => Arc::new(Kraken::new(&config.kraken_url)),
+            };
+            let backoff = config.backoff.clone();
+            exchange::spawn(adapter, symbols, backoff, tx.clone(), shutdown.clone());
+        }
+        drop(tx);
+
+        let mut book_interval = tokio::time::interval(config.book_interval);
+        let mut conflate_interval = tokio::time::interval(config.conflate_interval);
+        let mut checkpoint_interval = tokio::time::interval(config.checkpoint_interval);
+        let mut tick_interval = tokio::time::interval(Duration::from_secs(1));
+
+        let mut stopping = shutdown.clone();
+        let mut deadline: Option<tokio::time::Instant> = None;
+
+        loop {
+            let event = tokio::select! {
+                event = events.recv() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                _ = shutdown::stopped(&mut stopping), if deadline.is_none() => {
+                    deadline = Some(tokio::time::Instant::now() + config.shutdown_timeout);
+                    continue;
+                }
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)),
+                    if deadline.is_some() => {
+                    println!("gave up waiting for the websockets to close");
+                    break;
+                }
+                _ = book_interval.tick(), if books.is_some() => {
+                    if let (Some(books), Some(book_topic)) = (&books, &config.book_topic) {
+                        publish_books(&mut sinks, books, book_topic, config.book_depth);
+                    }
+                    continue;
+                }
+                _ = conflate_interval.tick(), if conflation.is_some() => {
+                    if let (Some(conflation), Some(conflate_topic)) =
+                        (&mut conflation, &config.conflate_topic)
+                    {
+                        publish_conflated(&mut sinks, conflation, conflate_topic);
+                    }
+                    continue;
+                }
+                changed = subscribed.changed(), if following => {
+                    if changed.is_err() {
+                        following = false;
+                        continue;
+                    }
+                    let updated: HashSet<String> =
+                        subscribed.borrow_and_update().iter().cloned().collect();
+                    for product_id in current.difference(&updated) {
+                        if let Some(books) = &mut books {
+                            books.remove(product_id);
+                        }
+                        if let Some(l3) = &l3 {
+                            l3.remove(product_id);
+                        }
+                    }
+                    current = updated;
+                    continue;
+                }
+                _ = tick_interval.tick() => {
+                    for sink in sinks.iter_mut() {
+                        if let Err(e) = sink.tick(Utc::now()) {
+                            println!("Failed flushing sink: {}", e);
+                        }
+                    }
+                    continue;
+                }
+                // so a crash loses at most an interval of progress
+                _ = checkpoint_interval.tick(), if config.checkpoint_file.is_some() => {
+                    if let Some(path) = &config.checkpoint_file {
+                        if let Err(e) = checkpoint.save(path) {
+                            println!("Failed saving the checkpoint: {}", e);
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            let received = Utc::now();
+            metrics::touch();
+
+            match event {
+                FeedEvent::Frame(frame) => {
+                    for sink in sinks.iter_mut() {
+                        if let Err(e) = sink.frame(&frame) {
+                            println!("Failed recording a {} frame: {}", frame.channel, e);
+                        }
+                    }
+                }
+                FeedEvent::Gap(gap) => {
+                    if backfill.is_some() {
+                        pending_backfill.insert(gap.product_id.clone());
+                    }
+                    publish_gap(&mut sinks, topic, channels, &gap, received);
+                }
+                FeedEvent::Status(status) => {
+                    if let Some(discovery) = &discovery {
+                        discovery.apply_status(&status);
+                    }
+                    // subscribed to for discovery only
+                    if !channels.contains(&Channel::Status) {
+                        continue;
+                    }
+
+                    metrics::MESSAGES_RECEIVED
+                        .with_label_values(&["all", Channel::Status.name()])
+                        .inc();
+
+                    let data = serde_json::to_string(&status).unwrap();
+                    let status_topic = topic.render(None, Channel::Status);
+                    let record = SinkRecord {
+                        topic: &status_topic,
+                        key: "status",
+                        channel: Channel::Status,
+                        received,
+                        sequence: None,
+                        time: None,
+                        payload: &data,
+                        marker: false,
+                    };
+                    publish(&mut sinks, &record);
+                }
+                FeedEvent::User(message) => {
+                    let product_id = message["product_id"].as_str().unwrap_or("all").to_owned();
+                    metrics::MESSAGES_RECEIVED
+                        .with_label_values(&[&product_id, Channel::User.name()])
+                        .inc();
+
+                    let data = message.to_string();
+                    let user_topic = config.user_topic.render(Some(&product_id), Channel::User);
+                    let record = SinkRecord {
+                        topic: &user_topic,
+                        key: &product_id,
+                        channel: Channel::User,
+                        received,
+                        sequence: message["sequence"].as_u64(),
+                        time: message["time"].as_str().and_then(|time| time.parse().ok()),
+                        payload: &data,
+                        marker: false,
+                    };
+                    publish(&mut sinks, &record);
+                }
+                FeedEvent::Normalized(normalized) => {
+                    let channel = match normalized {
+                        Normalized::Trade(_) => Channel::Trades,
+                        Normalized::Quote(_) => Channel::Quotes,
+                    };
+                    // symbols repeat across exchanges
+                    let key = format!("{}:{}", normalized.exchange(), normalized.symbol());
+                    metrics::MESSAGES_RECEIVED
+                        .with_label_values(&[&key, channel.name()])
+                        .inc();
+
+                    // trades without an id can't be told from each other,
+                    // they're all published
+                    if let Normalized::Trade(Trade { id: Some(id), .. }) = &normalized {
+                        if !normalized_ids.first_seen(channel, &key, id) {
+                            metrics::DUPLICATE_TRADES.inc();
+                            continue;
+                        }
+                    }
+
+                    let data = serde_json::to_string(&normalized).unwrap();
+                    let normalized_topic = config.normalized_topic.render_exchange(
+                        normalized.exchange(),
+                        Some(normalized.symbol()),
+                        channel,
+                    );
+                    let record = SinkRecord {
+                        topic: &normalized_topic,
+                        key: &key,
+                        channel,
+                        received,
+                        sequence: None,
+                        time: Some(normalized.ts()),
+                        payload: &data,
+                        marker: false,
+                    };
+                    publish(&mut sinks, &record);
+                }
+                FeedEvent::Message(Message::Error { message }) => println!("Error: {}", message),
+                FeedEvent::Message(msg) => {
+                    let (channel, product_id, data) = match (
+                        message_channel(&msg),
+                        message_product_id(&msg),
+                        message_payload(&msg),
+                    ) {
+                        (Some(channel), Some(product_id), Some(data)) => {
+                            (channel, product_id, data)
+                        }
+                        _ => {
+                            println!("{:?}", msg);
+                            continue;
+                        }
+                    };
+
+                    metrics::MESSAGES_RECEIVED
+                        .with_label_values(&[&product_id, channel.name()])
+                        .inc();
+
+                    // messages still in flight for a dropped market would
+                    // bring its book back
+                    let booked = (books.is_some() || l3.is_some())
+                        && subscribed.borrow().contains(&product_id);
+                    if let (Some(books), Message::Level2(level2)) = (&mut books, &msg) {
+                        if booked {
+                            books.apply(level2);
+                        }
+                    }
+                    let time = message_time(&msg);
+                    let sequence = message_sequence(&msg).map(|(_, sequence)| sequence as u64);
+                    let trade_id = message_trade_id(&msg);
+                    // the library parses the matches of the full channel as
+                    // their own message
+                    match (&l3, msg) {
+                        (Some(l3), Message::Full(full)) if booked => l3.send(full),
+                        (Some(l3), Message::Match(m)) if booked => l3.send(Full::Match(m)),
+                        _ => {}
+                    }
+
+                    // trades sent again after a reconnect count neither in
+                    // the conflated tickers nor downstream
+                    if let Some(trade_id) = trade_id {
+                        if !trade_ids.first_seen(channel, &product_id, &trade_id) {
+                            metrics::DUPLICATE_TRADES.inc();
+                            continue;
+                        }
+                    }
+
+                    // subscribed to for the books or the conflated tickers only
+                    if !channels.contains(&channel) {
+                        if let (Some(conflation), Channel::Ticker) = (&mut conflation, channel) {
+                            conflation.update(&product_id, sequence, time, &data);
+                        }
+                        continue;
+                    }
+
+                    // messages lost between the previous run and this one
+                    if let (Some(sequence), Some(last)) = (sequence, resumed.get(&product_id)) {
+                        if sequence > last + 1 {
+                            let gap = SequenceGap {
+                                kind: "gap",
+                                product_id: product_id.clone(),
+                                last_sequence: *last as usize,
+                                sequence: sequence as usize,
+                                missing: (sequence - last - 1) as usize,
+                            };
+                            println!(
+                                "{}: {} messages missed since the checkpoint",
+                                product_id, gap.missing
+                            );
+                            publish_gap(&mut sinks, topic, channels, &gap, received);
+                        }
+                        resumed.remove(&product_id);
+                    }
+
+                    if let (Some(backfill), Some(trade_id)) = (&backfill, trade_id) {
+                        // the trades between the last one published and this one
+                        // were lost while disconnected, or predate the start
+                        let since = if pending_backfill.remove(&product_id) {
+                            last_trades.get(&product_id).map(|last| Since::TradeId(*last))
+                        } else if !last_trades.contains_key(&product_id) {
+                            config.backfill_since.map(Since::Time)
+                        } else {
+                            None
+                        };
+                        if let Some(since) = since {
+                            match backfill.trades(&product_id, since, Some(trade_id)).await {
+                                Ok(trades) => publish_backfill(
+                                    &mut sinks,
+                                    topic,
+                                    channels,
+                                    &mut trade_ids,
+                                    conflation.as_mut(),
+                                    &product_id,
+                                    &trades,
+                                ),
+                                Err(e) => println!(
+                                    "{}: backfill failed, trades are lost: {}",
+                                    product_id, e
+                                ),
+                            }
+                        }
+
+                        let last = last_trades.entry(product_id.clone()).or_insert(trade_id);
+                        *last = trade_id.max(*last);
+                    }
+
+                    // after any backfilled trades, as it's newer
+                    if let (Some(conflation), Channel::Ticker) = (&mut conflation, channel) {
+                        conflation.update(&product_id, sequence, time, &data);
+                    }
+
+                    if let Some(time) = time {
+                        let lag = (received - time).num_microseconds().unwrap_or(0) as f64 / 1e6;
+                        metrics::WEBSOCKET_LAG
+                            .with_label_values(&[&product_id])
+                            .observe(lag.max(0.0));
+                    }
+
+                    // keyed by product so each market lands on a consistent
+                    // partition of its topic
+                    let channel_topic = topic.render(Some(&product_id), channel);
+                    let record = SinkRecord {
+                        topic: &channel_topic,
+                        key: &product_id,
+                        channel,
+                        received,
+                        sequence,
+                        time,
+                        payload: &data,
+                        marker: false,
+                    };
+                    publish(&mut sinks, &record);
+                    checkpoint.observe(&product_id, sequence, trade_id);
+                }
+            }
+        }
+
+        // shutdown was requested for the feeds to stop, so are the tasks
+        if tokio::time::timeout(config.shutdown_timeout, futures::future::join_all(tasks))
+            .await
+            .is_err()
+        {
+            println!("gave up waiting for the background tasks to stop");
+        }
+
+        // flush whatever is still buffered before exiting
+        let mut errors = Vec::new();
+        for sink in sinks {
+            if let Err(e) = sink.close().await {
+                errors.push(format!("failed closing sink: {}", e));
+            }
+        }
+
+        if let Some(path) = &config.checkpoint_file {
+            match checkpoint.save(path) {
+                Ok(()) => println!(
+                    "checkpointed {} markets to {}",
+                    checkpoint.products.len(),
+                    path.display()
+                ),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join(", "))
+        }
+    }
+}
+
+/// Lets consumers of every public channel know data was lost for a product
+fn publish_gap(
+    sinks: &mut [Box<dyn Sink>],
+    topic: &TopicTemplate,
+    channels: &[Channel],
+    gap: &SequenceGap,
+    received: DateTime<Utc>,
+) {
+    let data = serde_json::to_string(gap).unwrap();
+    let public = channels
+        .iter()
+        .filter(|c| !matches!(c, Channel::Status | Channel::User));
+    for channel in public {
+        let channel_topic = topic.render(Some(&gap.product_id), *channel);
+        let record = SinkRecord {
+            topic: &channel_topic,
+            key: &gap.product_id,
+            channel: *channel,
+            received,
+            sequence: Some(gap.sequence as u64),
+            time: None,
+            payload: &data,
+            marker: true,
+        };
+        publish(sinks, &record);
+    }
+}
+
+/// Publishes the top of every order book
+fn publish_books(
+    sinks: &mut [Box<dyn Sink>],
+    books: &OrderBooks,
+    topic: &TopicTemplate,
+    depth: usize,
+) {
+    let now = Utc::now();
+    for snapshot in books.snapshots(depth, now) {
+        let data = serde_json::to_string(&snapshot).unwrap();
+        let book_topic = topic.render(Some(&snapshot.product_id), Channel::Level2);
+        let record = SinkRecord {
+            topic: &book_topic,
+            key: &snapshot.product_id,
+            channel: Channel::Level2,
+            received: now,
+            sequence: None,
+            time: Some(snapshot.time),
+            payload: &data,
+            marker: true,
+        };
+        publish(sinks, &record);
+    }
+}
+
+/// Publishes the latest ticker of every product that ticked since the last time
+fn publish_conflated(
+    sinks: &mut [Box<dyn Sink>],
+    conflation: &mut Conflation,
+    topic: &TopicTemplate,
+) {
+    let now = Utc::now();
+    for ticker in conflation.drain() {
+        let conflate_topic = topic.render(Some(&ticker.product_id), Channel::Ticker);
+        let record = SinkRecord {
+            topic: &conflate_topic,
+            key: &ticker.product_id,
+            channel: Channel::Ticker,
+            received: now,
+            sequence: ticker.sequence,
+            time: ticker.time,
+            payload: &ticker.payload,
+            marker: true,
+        };
+        publish(sinks, &record);
+    }
+}
+
+/// Publishes the backfilled trades of a product, oldest first, to every trade
+/// channel being published, skipping those published already, and counts
+/// them in the conflated tickers. The live feed waits for the backfill so
+/// trades stay in order.
+fn publish_backfill(
+    sinks: &mut [Box<dyn Sink>],
+    topic: &TopicTemplate,
+    channels: &[Channel],
+    trade_ids: &mut TradeIds<u64>,
+    mut conflation: Option<&mut Conflation>,
+    product_id: &str,
+    trades: &[RestTrade],
+) {
+    println!("{}: backfilled {} trades", product_id, trades.len());
+    metrics::BACKFILLED.inc_by(trades.len() as u64);
+
+    let received = Utc::now();
+    let trade_channels: Vec<Channel> = channels
+        .iter()
+        .copied()
+        .filter(|c| matches!(c, Channel::Ticker | Channel::Matches))
+        .collect();
+
+    for trade in trades {
+        let data = serde_json::to_string(&BackfilledTrade::new(product_id, trade)).unwrap();
+        for channel in &trade_channels {
+            if !trade_ids.first_seen(*channel, product_id, &trade.trade_id) {
+                metrics::DUPLICATE_TRADES.inc();
+                continue;
+            }
+            if let (Some(conflation), Channel::Ticker) = (&mut conflation, channel) {
+                conflation.backfilled(product_id, trade.size);
+            }
+            let channel_topic = topic.render(Some(product_id), *channel);
+            let record = SinkRecord {
+                topic: &channel_topic,
+                key: product_id,
+                channel: *channel,
+                received,
+                sequence: None,
+                time: Some(trade.time),
+                payload: &data,
+                marker: true,
+            };
+            publish(sinks, &record);
+        }
+    }
+}
+
+fn publish(sinks: &mut [Box<dyn Sink>], record: &SinkRecord) {
+    for sink in sinks.iter_mut() {
+        if let Err(e) = sink.publish(record) {
+            println!("Failed publishing to {}: {}", record.topic, e);
+        }
+    }
+}
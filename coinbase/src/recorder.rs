@@ -0,0 +1,220 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Timelike, Utc};
+use flate2::write::GzEncoder;
+use serde::de::IgnoredAny;
+
+/// How recorded feed files are compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn extension(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "jsonl.gz",
+            Compression::Zstd => "jsonl.zst",
+        }
+    }
+}
+
+/// A compressed file being written
+enum Writer {
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl Writer {
+    fn create(path: &Path, compression: Compression) -> io::Result<Self> {
+        // append so a new segment or a restart within the hour adds a new
+        // compressed frame instead of truncating, both formats read
+        // concatenated frames back
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let file = BufWriter::new(file);
+
+        Ok(match compression {
+            Compression::Gzip => Writer::Gzip(GzEncoder::new(file, flate2::Compression::default())),
+            Compression::Zstd => Writer::Zstd(zstd::Encoder::new(file, 0)?),
+        })
+    }
+
+    /// Writes the compression trailer and flushes the file
+    fn finish(self) -> io::Result<()> {
+        let mut file = match self {
+            Writer::Gzip(w) => w.finish()?,
+            Writer::Zstd(w) => w.finish()?,
+        };
+        file.flush()
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Writer::Gzip(w) => w.write(buf),
+            Writer::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Writer::Gzip(w) => w.flush(),
+            Writer::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+/// Archives every feed message as a JSON line into hourly compressed files
+/// named `coinbase-<YYYYmmdd-HH>.jsonl.<gz|zst>`. Each line looks like
+/// `{"received":"<rfc3339>","channel":"ticker","message":{..}}`, a message
+/// that isn't json is kept as a string.
+///
+/// Files are written in segments of `segment` each, every one a complete
+/// compressed frame, so the feed is readable up to the last finished segment
+/// while the hour is still being recorded.
+pub struct FeedRecorder {
+    dir: PathBuf,
+    compression: Compression,
+    segment: Duration,
+    hour: Option<DateTime<Utc>>,
+    writer: Option<Writer>,
+    /// when the open segment was started
+    started: Option<DateTime<Utc>>,
+}
+
+impl FeedRecorder {
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        compression: Compression,
+        segment: Duration,
+    ) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            dir,
+            compression,
+            segment,
+            hour: None,
+            writer: None,
+            started: None,
+        })
+    }
+
+    /// Appends one message, rotating to a new file when the hour changes
+    pub fn record(&mut self, received: DateTime<Utc>, channel: &str, message: &str) -> io::Result<()> {
+        let hour = received
+            .with_minute(0)
+            .and_then(|t| t.with_second(0))
+            .and_then(|t| t.with_nanosecond(0))
+            .unwrap_or(received);
+
+        if self.hour != Some(hour) {
+            self.close()?;
+            println!("recording feed to {}", self.path(hour).display());
+            self.hour = Some(hour);
+        }
+        if self.writer.is_none() {
+            self.writer = Some(Writer::create(&self.path(hour), self.compression)?);
+            self.started = Some(Utc::now());
+        }
+
+        let writer = self.writer.as_mut().expect("recorder file is open");
+        // the message is json already, splice it in rather than re-encoding it
+        let quoted;
+        let message = if serde_json::from_str::<IgnoredAny>(message).is_ok() {
+            message
+        } else {
+            quoted = serde_json::to_string(message).unwrap();
+            &quoted
+        };
+        writeln!(
+            writer,
+            "{{\"received\":{},\"channel\":{},\"message\":{}}}",
+            serde_json::to_string(&received).unwrap(),
+            serde_json::to_string(channel).unwrap(),
+            message
+        )
+    }
+
+    /// Finishes the open segment once it's older than the segment length,
+    /// the next message starts a new one in the same file
+    pub fn finish_segment(&mut self, now: DateTime<Utc>) -> io::Result<()> {
+        let due = match self.started {
+            Some(started) => (now - started).to_std().unwrap_or(Duration::ZERO) >= self.segment,
+            None => false,
+        };
+        if !due {
+            return Ok(());
+        }
+
+        self.started = None;
+        match self.writer.take() {
+            Some(writer) => writer.finish(),
+            None => Ok(()),
+        }
+    }
+
+    fn path(&self, hour: DateTime<Utc>) -> PathBuf {
+        let extension = self.compression.extension();
+        self.dir.join(format!("coinbase-{}.{}", hour.format("%Y%m%d-%H"), extension))
+    }
+
+    /// Finishes the current file, if any
+    pub fn close(&mut self) -> io::Result<()> {
+        self.hour = None;
+        self.started = None;
+        match self.writer.take() {
+            Some(writer) => writer.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for FeedRecorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            println!("Failed closing feed recording: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn finishes_segments_readable_while_the_hour_runs() {
+        let dir = std::env::temp_dir().join(format!("coinbase-recorder-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut recorder = FeedRecorder::new(&dir, Compression::Gzip, Duration::ZERO).unwrap();
+
+        let received = "2023-10-15T14:00:05Z".parse().unwrap();
+        let frame = r#"{"type":"ticker","product_id":"BTC-USD","unmodeled":[1,2]}"#;
+        recorder.record(received, "ticker", frame).unwrap();
+        recorder.record(received, "feed", "not json").unwrap();
+        recorder.finish_segment(Utc::now()).unwrap();
+
+        // read back while the recorder still runs, without closing it
+        let path = dir.join("coinbase-20231015-14.jsonl.gz");
+        let mut data = String::new();
+        flate2::read::MultiGzDecoder::new(File::open(&path).unwrap())
+            .read_to_string(&mut data)
+            .unwrap();
+        let lines: Vec<serde_json::Value> =
+            data.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["message"]["unmodeled"], serde_json::json!([1, 2]));
+        assert_eq!(lines[1]["message"], "not json");
+
+        drop(recorder);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use coinbase_pro_rs::structs::wsfeed::{Message, Ticker};
+use feed_schema::Format;
+use serde::Deserialize;
+
+use crate::envelope;
+use crate::feed::{message_payload, message_sequence, message_time, ticker_product_id, Channel};
+use crate::producer::FeedProducer;
+use crate::topic::TopicTemplate;
+
+/// How fast a recording is replayed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speed {
+    /// as fast as the producer takes it
+    Max,
+    /// the original timing divided by this factor
+    Factor(f64),
+}
+
+impl FromStr for Speed {
+    type Err = String;
+
+    /// Parses 'max', '10x' or '10'
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("max") {
+            return Ok(Speed::Max);
+        }
+
+        let factor: f64 = s
+            .trim_end_matches(['x', 'X'])
+            .parse()
+            .map_err(|_| format!("invalid speed '{}', expected e.g. '1x', '10x' or 'max'", s))?;
+
+        if factor <= 0.0 || !factor.is_finite() {
+            return Err(format!("speed must be positive, got '{}'", s));
+        }
+
+        Ok(Speed::Factor(factor))
+    }
+}
+
+/// One line of a recording written by the `FeedRecorder`
+#[derive(Debug, Deserialize)]
+struct RecordedLine {
+    received: DateTime<Utc>,
+    channel: String,
+    message: serde_json::Value,
+}
+
+/// Opens a recording, picking the decompressor from the file extension
+fn open(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    let file = File::open(path)?;
+    let reader: Box<dyn Read> = match path.extension().and_then(|e| e.to_str()) {
+        // recordings appended to after a restart hold several gzip members
+        Some("gz") => Box::new(flate2::read::MultiGzDecoder::new(file)),
+        Some("zst") => Box::new(zstd::Decoder::new(file)?),
+        _ => Box::new(file),
+    };
+    Ok(Box::new(BufReader::new(reader)))
+}
+
+/// Publishes the recorded tickers in `path` to kafka encoded as `format`,
+/// keyed by product, waiting between messages as long as they were apart
+/// when recorded scaled by `speed`. Returns the number of tickers published.
+pub async fn replay(
+    path: &Path,
+    producer: &FeedProducer,
+    topic: &TopicTemplate,
+    speed: Speed,
+    format: Format,
+) -> io::Result<usize> {
+    let reader = open(path)?;
+    let mut previous: Option<DateTime<Utc>> = None;
+    let mut count = 0;
+
+    for (n, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let recorded: RecordedLine = match serde_json::from_str(&line) {
+            Ok(recorded) => recorded,
+            Err(e) => {
+                println!("{}:{}: skipping unreadable line: {}", path.display(), n + 1, e);
+                continue;
+            }
+        };
+
+        if recorded.channel != Channel::Ticker.name() {
+            continue;
+        }
+
+        // make sure the consumer will be able to parse it
+        let ticker: Ticker = match serde_json::from_value(recorded.message.clone()) {
+            Ok(ticker) => ticker,
+            Err(e) => {
+                println!("{}:{}: skipping invalid ticker: {}", path.display(), n + 1, e);
+                continue;
+            }
+        };
+
+        if let (Speed::Factor(factor), Some(previous)) = (speed, previous) {
+            let elapsed = (recorded.received - previous).to_std().unwrap_or(Duration::ZERO);
+            tokio::time::sleep(elapsed.div_f64(factor)).await;
+        }
+        previous = Some(recorded.received);
+
+        let product_id = ticker_product_id(&ticker).to_owned();
+        let channel_topic = topic.render(Some(&product_id), Channel::Ticker);
+
+        // the same envelope the live feed produces, received when it was recorded
+        let msg = Message::Ticker(ticker);
+        let meta = envelope::metadata(
+            &product_id,
+            Channel::Ticker.name(),
+            message_sequence(&msg).map(|(_, sequence)| sequence as u64),
+            message_time(&msg),
+            recorded.received,
+        );
+        // recordings keep the frames as coinbase sent them, publish the
+        // ticker the way the live feed does
+        let payload = message_payload(&msg).unwrap_or_else(|| recorded.message.to_string());
+        let data = envelope::encode(format, meta, &payload)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        producer
+            .send(&channel_topic, &product_id, data)
+            .map_err(io::Error::other)?;
+
+        count += 1;
+    }
+
+    Ok(count)
+}
@@ -0,0 +1,59 @@
+use std::path::PathBuf;
+
+pub use kafka_security::{SaslConfig, SecurityConfig, TlsConfig};
+
+/// Kafka connection security, shared by the feed and `replay`
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct KafkaSecurity {
+    /// Connect to kafka over TLS, implied by the other --kafka-tls options
+    #[arg(long)]
+    pub kafka_tls: bool,
+    /// CA certificate (PEM) the brokers are verified with, the system roots
+    /// are used when not set
+    #[arg(long)]
+    pub kafka_ca_file: Option<PathBuf>,
+    /// Client certificate (PEM) for mutual TLS
+    #[arg(long, requires = "kafka_key_file")]
+    pub kafka_cert_file: Option<PathBuf>,
+    /// Private key (PEM) of the client certificate
+    #[arg(long, requires = "kafka_cert_file")]
+    pub kafka_key_file: Option<PathBuf>,
+    /// Skip checking that broker certificates match their host name
+    #[arg(long)]
+    pub kafka_no_verify_hostname: bool,
+    /// SASL mechanism: PLAIN, SCRAM-SHA-256 or SCRAM-SHA-512, over TLS when
+    /// the --kafka-tls options are set too
+    #[arg(long, requires_all = ["kafka_sasl_username", "kafka_sasl_password"])]
+    pub kafka_sasl_mechanism: Option<String>,
+    /// SASL user name
+    #[arg(long, requires = "kafka_sasl_mechanism")]
+    pub kafka_sasl_username: Option<String>,
+    /// SASL password, better set as COINBASE_KAFKA_SASL_PASSWORD or in the
+    /// --config file than on the command line
+    #[arg(long, requires = "kafka_sasl_mechanism")]
+    pub kafka_sasl_password: Option<String>,
+}
+
+impl KafkaSecurity {
+    /// The TLS and SASL settings, plaintext when none is given
+    pub fn security(&self) -> Result<SecurityConfig, String> {
+        let tls_enabled = self.kafka_tls
+            || self.kafka_ca_file.is_some()
+            || self.kafka_cert_file.is_some()
+            || self.kafka_no_verify_hostname;
+        let tls = tls_enabled.then(|| TlsConfig {
+            ca_file: self.kafka_ca_file.clone(),
+            cert_file: self.kafka_cert_file.clone(),
+            key_file: self.kafka_key_file.clone(),
+            verify_hostname: !self.kafka_no_verify_hostname,
+        });
+
+        let sasl = SaslConfig::new(
+            self.kafka_sasl_mechanism.clone(),
+            self.kafka_sasl_username.clone(),
+            self.kafka_sasl_password.clone(),
+        )?;
+
+        Ok(SecurityConfig { tls, sasl })
+    }
+}
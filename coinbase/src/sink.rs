@@ -0,0 +1,314 @@
+use chrono::{DateTime, Utc};
+use futures::future::{self, BoxFuture};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+use feed_schema::Format;
+
+use crate::envelope;
+use crate::feed::{Channel, Frame};
+use crate::producer::FeedProducer;
+use crate::recorder::FeedRecorder;
+
+/// The transports feed records can be published to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SinkKind {
+    /// kafka topics, see --topic
+    Kafka,
+    /// print records, a dry run without any broker
+    Stdout,
+    /// the raw websocket frames in hourly compressed files, see --record-dir
+    File,
+    /// nats subjects '<topic>.<product>', see --nats-url
+    Nats,
+}
+
+/// A feed record on its way to a sink
+#[derive(Debug)]
+pub struct SinkRecord<'a> {
+    /// the rendered topic
+    pub topic: &'a str,
+    /// the product id, or 'status' for status messages
+    pub key: &'a str,
+    pub channel: Channel,
+    pub received: DateTime<Utc>,
+    /// exchange sequence of the message, if it has one
+    pub sequence: Option<u64>,
+    /// when the exchange says the message happened
+    pub time: Option<DateTime<Utc>>,
+    /// the json message
+    pub payload: &'a str,
+    /// set for markers the producer generates itself (like sequence gaps)
+    /// rather than messages received from the exchange
+    pub marker: bool,
+}
+
+impl SinkRecord<'_> {
+    /// Our own orders from the user channel, only kafka (and in process
+    /// consumers) get them so access stays limited to the user topic
+    pub fn is_private(&self) -> bool {
+        self.channel == Channel::User
+    }
+}
+
+/// Somewhere feed records are published to
+pub trait Sink: Send {
+    fn publish(&mut self, record: &SinkRecord) -> Result<(), String>;
+
+    /// A websocket frame as received, ahead of the records published from
+    /// it. Only archives of the feed keep these.
+    fn frame(&mut self, _frame: &Frame) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Called about once a second, for sinks writing on a timer
+    fn tick(&mut self, _now: DateTime<Utc>) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Flushes anything buffered, awaited once before exiting
+    fn close(self: Box<Self>) -> BoxFuture<'static, Result<(), String>>;
+}
+
+/// Publishes records to kafka encoded as `format`
+pub struct KafkaSink {
+    producer: FeedProducer,
+    format: Format,
+}
+
+impl KafkaSink {
+    pub fn new(producer: FeedProducer, format: Format) -> Self {
+        Self { producer, format }
+    }
+}
+
+impl Sink for KafkaSink {
+    fn publish(&mut self, record: &SinkRecord) -> Result<(), String> {
+        let meta = envelope::metadata(
+            record.key,
+            record.channel.name(),
+            record.sequence,
+            record.time,
+            record.received,
+        );
+        let data = envelope::encode(self.format, meta, record.payload)?;
+        self.producer.send(record.topic, record.key, data)
+    }
+
+    fn close(self: Box<Self>) -> BoxFuture<'static, Result<(), String>> {
+        // joining the producer thread blocks until the spool is written
+        let close = tokio::task::spawn_blocking(move || self.producer.close());
+        Box::pin(async move { close.await.map_err(|e| e.to_string()) })
+    }
+}
+
+impl Sink for FeedRecorder {
+    fn publish(&mut self, _record: &SinkRecord) -> Result<(), String> {
+        // the recording is an archive of the frames the exchange sent, the
+        // records are parsed from them
+        Ok(())
+    }
+
+    fn frame(&mut self, frame: &Frame) -> Result<(), String> {
+        self.record(frame.received, frame.channel, &frame.text)
+            .map_err(|e| e.to_string())
+    }
+
+    fn tick(&mut self, now: DateTime<Utc>) -> Result<(), String> {
+        self.finish_segment(now).map_err(|e| e.to_string())
+    }
+
+    fn close(mut self: Box<Self>) -> BoxFuture<'static, Result<(), String>> {
+        Box::pin(future::ready(
+            FeedRecorder::close(&mut self).map_err(|e| e.to_string()),
+        ))
+    }
+}
+
+/// Prints every record, handy to run the producer without a broker
+pub struct StdoutSink;
+
+impl Sink for StdoutSink {
+    fn publish(&mut self, record: &SinkRecord) -> Result<(), String> {
+        if record.is_private() {
+            return Ok(());
+        }
+        println!("{} {} {}", record.topic, record.key, record.payload);
+        Ok(())
+    }
+
+    fn close(self: Box<Self>) -> BoxFuture<'static, Result<(), String>> {
+        Box::pin(future::ok(()))
+    }
+}
+
+/// Publishes records to nats, on the subject '<topic>.<product>' so
+/// subscribers can pick markets with wildcards like 'coinbase-ticker.*'
+pub struct NatsSink {
+    tx: UnboundedSender<(String, Vec<u8>)>,
+    handle: JoinHandle<()>,
+}
+
+impl NatsSink {
+    pub async fn connect(url: &str) -> Result<Self, String> {
+        let client = async_nats::connect(url)
+            .await
+            .map_err(|e| format!("failed connecting to nats at {}: {}", url, e))?;
+
+        // the nats client is async, publish from a task so the sink never blocks the feed
+        let (tx, mut rx) = mpsc::unbounded_channel::<(String, Vec<u8>)>();
+        let handle = tokio::spawn(async move {
+            while let Some((subject, payload)) = rx.recv().await {
+                if let Err(e) = client.publish(subject, payload.into()).await {
+                    println!("Failed publishing to nats: {}", e);
+                }
+            }
+            if let Err(e) = client.flush().await {
+                println!("Failed flushing nats: {}", e);
+            }
+        });
+
+        Ok(Self { tx, handle })
+    }
+}
+
+impl Sink for NatsSink {
+    fn publish(&mut self, record: &SinkRecord) -> Result<(), String> {
+        // the subjects aren't access controlled like the user topic
+        if record.is_private() {
+            return Ok(());
+        }
+        let subject = format!("{}.{}", record.topic, record.key);
+        self.tx
+            .send((subject, record.payload.as_bytes().to_vec()))
+            .map_err(|_| "nats publisher has stopped".to_string())
+    }
+
+    fn close(self: Box<Self>) -> BoxFuture<'static, Result<(), String>> {
+        let NatsSink { tx, handle } = *self;
+        // dropping the sender lets the task drain and flush
+        drop(tx);
+        Box::pin(async move { handle.await.map_err(|e| e.to_string()) })
+    }
+}
+
+/// A record handed over by the [`ChannelSink`]
+#[derive(Debug, Clone)]
+pub struct PublishedRecord {
+    pub topic: String,
+    pub key: String,
+    pub channel: Channel,
+    pub received: DateTime<Utc>,
+    pub sequence: Option<u64>,
+    pub time: Option<DateTime<Utc>>,
+    pub payload: String,
+    pub marker: bool,
+}
+
+impl PublishedRecord {
+    /// The record as the kafka sink would send it, with its metadata
+    pub fn encode(&self, format: Format) -> Result<Vec<u8>, String> {
+        let meta = envelope::metadata(
+            &self.key,
+            self.channel.name(),
+            self.sequence,
+            self.time,
+            self.received,
+        );
+        envelope::encode(format, meta, &self.payload)
+    }
+}
+
+/// Hands every record to a channel, to consume the feed in process
+pub struct ChannelSink {
+    tx: UnboundedSender<PublishedRecord>,
+}
+
+impl ChannelSink {
+    /// The sink and the receiving end of its records
+    pub fn channel() -> (Self, UnboundedReceiver<PublishedRecord>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Self { tx }, rx)
+    }
+}
+
+impl Sink for ChannelSink {
+    fn publish(&mut self, record: &SinkRecord) -> Result<(), String> {
+        let record = PublishedRecord {
+            topic: record.topic.to_owned(),
+            key: record.key.to_owned(),
+            channel: record.channel,
+            received: record.received,
+            sequence: record.sequence,
+            time: record.time,
+            payload: record.payload.to_owned(),
+            marker: record.marker,
+        };
+        self.tx
+            .send(record)
+            .map_err(|_| "the channel receiver is gone".to_string())
+    }
+
+    fn close(self: Box<Self>) -> BoxFuture<'static, Result<(), String>> {
+        Box::pin(future::ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::recorder::Compression;
+
+    fn record<'a>(channel: Channel, topic: &'a str, payload: &'a str) -> SinkRecord<'a> {
+        SinkRecord {
+            topic,
+            key: "BTC-USD",
+            channel,
+            received: "2023-10-15T14:00:05Z".parse().unwrap(),
+            sequence: None,
+            time: None,
+            payload,
+            marker: false,
+        }
+    }
+
+    #[test]
+    fn user_messages_stay_out_of_the_recording() {
+        let dir = std::env::temp_dir().join(format!("coinbase-sink-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let segment = Duration::from_secs(60);
+        let mut recorder: Box<dyn Sink> =
+            Box::new(FeedRecorder::new(&dir, Compression::Gzip, segment).unwrap());
+
+        let order = r#"{"type":"received","order_id":"abc","product_id":"BTC-USD"}"#;
+        let user = record(Channel::User, "coinbase-user-BTC-USD", order);
+        assert!(user.is_private());
+        recorder.publish(&user).unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        let ticker = Frame {
+            received: "2023-10-15T14:00:05Z".parse().unwrap(),
+            channel: "ticker",
+            text: r#"{"type":"ticker","unmodeled":true}"#.to_string(),
+        };
+        recorder.frame(&ticker).unwrap();
+        futures::executor::block_on(recorder.close()).unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn user_messages_reach_in_process_consumers() {
+        let (sink, mut rx) = ChannelSink::channel();
+        let mut sink: Box<dyn Sink> = Box::new(sink);
+        let order = r#"{"type":"done","order_id":"abc","product_id":"BTC-USD"}"#;
+        sink.publish(&record(Channel::User, "coinbase-user-BTC-USD", order)).unwrap();
+
+        let published = rx.try_recv().unwrap();
+        assert_eq!(published.topic, "coinbase-user-BTC-USD");
+        assert_eq!(published.payload, order);
+    }
+}
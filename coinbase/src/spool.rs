@@ -0,0 +1,266 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// A record waiting in the spool
+#[derive(Debug, Clone)]
+pub struct SpooledRecord {
+    pub topic: String,
+    pub key: String,
+    pub value: Vec<u8>,
+}
+
+/// An on-disk write-ahead buffer for records kafka didn't take.
+///
+/// Records are appended to `<dir>/producer.spool` as length prefixed frames
+/// and read back in the same order. How far the spool has been drained is
+/// kept in `<dir>/producer.spool.offset`, so records survive a restart and
+/// are not sent twice. Once everything is drained the spool is truncated.
+pub struct Spool {
+    path: PathBuf,
+    offset_path: PathBuf,
+    file: File,
+    /// byte offset of the next record to drain
+    offset: u64,
+    /// byte length of the spool file
+    len: u64,
+    /// number of records not drained yet
+    depth: usize,
+    max_bytes: u64,
+}
+
+impl Spool {
+    /// Opens (or creates) the spool in `dir`, picking up where a previous run left off
+    pub fn open(dir: &Path, max_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join("producer.spool");
+        let offset_path = dir.join("producer.spool.offset");
+
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        let len = file.metadata()?.len();
+
+        let offset = match fs::read_to_string(&offset_path) {
+            Ok(offset) => offset.trim().parse().unwrap_or(0).min(len),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+
+        let mut spool = Self {
+            path,
+            offset_path,
+            file,
+            offset,
+            len,
+            depth: 0,
+            max_bytes,
+        };
+        spool.recover()?;
+
+        if spool.depth > 0 {
+            println!(
+                "spool {} has {} records left from a previous run",
+                spool.path.display(),
+                spool.depth
+            );
+        }
+
+        Ok(spool)
+    }
+
+    /// Number of records waiting to be drained
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.depth == 0
+    }
+
+    /// Appends a record, returns false when the spool is full and the record was dropped
+    pub fn push(&mut self, record: &SpooledRecord) -> io::Result<bool> {
+        let size = frame_size(record);
+        if self.len - self.offset + size > self.max_bytes {
+            return Ok(false);
+        }
+
+        if let Err(e) = write_record(&self.file, record) {
+            // cut off what made it to the file, the next frame has to start
+            // where this one did
+            self.file.set_len(self.len)?;
+            return Err(e);
+        }
+
+        self.len += size;
+        self.depth += 1;
+        Ok(true)
+    }
+
+    /// Waits for the records pushed so far to reach the disk
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Marks every record before `offset` as drained
+    pub fn advance(&mut self, offset: u64, records: usize) -> io::Result<()> {
+        self.offset = offset;
+        self.depth = self.depth.saturating_sub(records);
+
+        if self.depth == 0 {
+            // fully drained, start over with an empty file
+            self.file.set_len(0)?;
+            self.offset = 0;
+            self.len = 0;
+        }
+
+        fs::write(&self.offset_path, self.offset.to_string())
+    }
+
+    /// Reads up to `max` of the oldest records, with the offset right after each
+    /// one to pass to `advance` once it was sent
+    pub fn peek(&self, max: usize) -> io::Result<Vec<(SpooledRecord, u64)>> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut reader = BufReader::new(file);
+
+        let mut records = Vec::new();
+        let mut offset = self.offset;
+
+        while records.len() < max && offset < self.len {
+            let record = match read_record(&mut reader) {
+                Ok(record) => record,
+                // a frame cut short by a crash mid write, nothing after it is usable
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            offset += frame_size(&record);
+            records.push((record, offset));
+        }
+
+        Ok(records)
+    }
+
+    /// Counts the records left to drain and cuts off a frame left half
+    /// written by a crash, so new records are appended after a whole one
+    fn recover(&mut self) -> io::Result<()> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut reader = BufReader::new(file);
+        let mut end = self.offset;
+
+        while end < self.len {
+            match read_record(&mut reader) {
+                Ok(record) => {
+                    end += frame_size(&record);
+                    self.depth += 1;
+                }
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        if end < self.len {
+            println!("truncating {} partial bytes from {}", self.len - end, self.path.display());
+            self.file.set_len(end)?;
+            self.len = end;
+        }
+
+        Ok(())
+    }
+}
+
+fn frame_size(record: &SpooledRecord) -> u64 {
+    (12 + record.topic.len() + record.key.len() + record.value.len()) as u64
+}
+
+fn write_frame(writer: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(data)
+}
+
+fn write_record(file: &File, record: &SpooledRecord) -> io::Result<()> {
+    let mut writer = BufWriter::new(file);
+    write_frame(&mut writer, record.topic.as_bytes())?;
+    write_frame(&mut writer, record.key.as_bytes())?;
+    write_frame(&mut writer, &record.value)?;
+    writer.flush()
+}
+
+fn read_frame(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let mut data = vec![0u8; u32::from_be_bytes(len) as usize];
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn read_record(reader: &mut impl Read) -> io::Result<SpooledRecord> {
+    let invalid = |_| io::Error::new(io::ErrorKind::InvalidData, "spooled record isn't utf8");
+    Ok(SpooledRecord {
+        topic: String::from_utf8(read_frame(reader)?).map_err(invalid)?,
+        key: String::from_utf8(read_frame(reader)?).map_err(invalid)?,
+        value: read_frame(reader)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(value: &str) -> SpooledRecord {
+        SpooledRecord {
+            topic: "coinbase-ticker".to_string(),
+            key: "BTC-USD".to_string(),
+            value: value.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn keeps_whole_frames_across_a_crash() {
+        let dir = std::env::temp_dir().join(format!("coinbase-spool-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut spool = Spool::open(&dir, 1024).unwrap();
+        assert!(spool.push(&record("first")).unwrap());
+        assert!(spool.push(&record("second")).unwrap());
+        spool.sync().unwrap();
+        drop(spool);
+
+        // a frame half written when the process died
+        let mut file = OpenOptions::new().append(true).open(dir.join("producer.spool")).unwrap();
+        file.write_all(&[0, 0, 0, 15, b'c', b'o']).unwrap();
+        drop(file);
+
+        let mut spool = Spool::open(&dir, 1024).unwrap();
+        assert_eq!(spool.depth(), 2);
+        assert!(spool.push(&record("third")).unwrap());
+
+        let records = spool.peek(10).unwrap();
+        let values: Vec<&[u8]> = records.iter().map(|(r, _)| r.value.as_slice()).collect();
+        assert_eq!(values, vec![&b"first"[..], b"second", b"third"]);
+
+        let (_, end) = records[0];
+        spool.advance(end, 1).unwrap();
+        drop(spool);
+        let spool = Spool::open(&dir, 1024).unwrap();
+        assert_eq!(spool.depth(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drops_records_beyond_the_limit() {
+        let dir = std::env::temp_dir().join(format!("coinbase-spool-full-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut spool = Spool::open(&dir, 40).unwrap();
+        assert!(spool.push(&record("1")).unwrap());
+        assert!(!spool.push(&record("2")).unwrap());
+        assert_eq!(spool.depth(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
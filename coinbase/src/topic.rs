@@ -0,0 +1,63 @@
+use crate::feed::Channel;
+
+/// A kafka topic name with `{product}`, `{channel}` and `{exchange}`
+/// placeholders, e.g. `coinbase-{product}-{channel}`
+#[derive(Debug, Clone)]
+pub struct TopicTemplate {
+    template: String,
+}
+
+impl TopicTemplate {
+    const PLACEHOLDERS: [&'static str; 3] = ["{product}", "{channel}", "{exchange}"];
+
+    /// Validates a template, used as the clap value parser
+    pub fn parse(template: &str) -> Result<Self, String> {
+        if template.is_empty() {
+            return Err("topic can't be empty".to_string());
+        }
+
+        // strip the known placeholders, anything braced left over is a typo
+        let mut rest = template.to_owned();
+        for placeholder in Self::PLACEHOLDERS {
+            rest = rest.replace(placeholder, "");
+        }
+        if rest.contains('{') || rest.contains('}') {
+            return Err(format!(
+                "unknown placeholder in '{}', expected {}",
+                template,
+                Self::PLACEHOLDERS.join(", ")
+            ));
+        }
+
+        Ok(Self {
+            template: template.to_owned(),
+        })
+    }
+
+    /// The topic for a product's messages on a channel. Messages that don't
+    /// belong to a product (like `status`) use 'all'
+    pub fn render(&self, product_id: Option<&str>, channel: Channel) -> String {
+        self.render_exchange("coinbase", product_id, channel)
+    }
+
+    /// The topic for a product's messages from any exchange. Kafka topics
+    /// can't hold a '/', pairs like 'XBT/USD' become 'XBT-USD'
+    pub fn render_exchange(
+        &self,
+        exchange: &str,
+        product_id: Option<&str>,
+        channel: Channel,
+    ) -> String {
+        let product = product_id.unwrap_or("all").replace('/', "-");
+        self.template
+            .replace("{exchange}", exchange)
+            .replace("{product}", &product)
+            .replace("{channel}", channel.name())
+    }
+}
+
+impl std::fmt::Display for TopicTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.template)
+    }
+}
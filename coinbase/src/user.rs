@@ -0,0 +1,319 @@
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::Utc;
+use futures::future::{self, BoxFuture};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::watch;
+
+use crate::feed::{Backoff, FeedEvent};
+use crate::websocket::{self, Flow, Session, Update};
+
+/// What coinbase signs websocket subscriptions against
+const VERIFY_PATH: &str = "/users/self/verify";
+
+/// Coinbase API credentials for the authenticated `user` channel
+#[derive(Clone, Deserialize)]
+pub struct Credentials {
+    pub key: String,
+    /// base64 encoded, as coinbase hands it out
+    pub secret: String,
+    pub passphrase: String,
+}
+
+// keep the secret out of logs
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Credentials").field("key", &self.key).finish()
+    }
+}
+
+impl Credentials {
+    /// Reads `{"key": ..., "secret": ..., "passphrase": ...}` from a json file
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed reading {}: {}", path.display(), e))?;
+        serde_json::from_str(&data).map_err(|e| format!("invalid {}: {}", path.display(), e))
+    }
+
+    /// Reads COINBASE_API_KEY, COINBASE_API_SECRET and COINBASE_API_PASSPHRASE
+    pub fn from_env() -> Result<Self, String> {
+        let var = |name: &str| std::env::var(name).map_err(|_| format!("{} is not set", name));
+        Ok(Self {
+            key: var("COINBASE_API_KEY")?,
+            secret: var("COINBASE_API_SECRET")?,
+            passphrase: var("COINBASE_API_PASSPHRASE")?,
+        })
+    }
+
+    /// base64(hmac-sha256(base64 decoded secret, timestamp + method + path))
+    pub fn sign(&self, timestamp: &str, method: &str, path: &str) -> Result<String, String> {
+        let secret = BASE64
+            .decode(&self.secret)
+            .map_err(|e| format!("api secret isn't base64: {}", e))?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&secret).map_err(|e| e.to_string())?;
+        mac.update(format!("{}{}{}", timestamp, method, path).as_bytes());
+        Ok(BASE64.encode(mac.finalize().into_bytes()))
+    }
+
+    /// A signed subscription to the `user` channel for `product_ids`
+    fn subscribe(&self, product_ids: &[String]) -> Result<String, String> {
+        let timestamp = Utc::now().timestamp().to_string();
+        let signature = self.sign(&timestamp, "GET", VERIFY_PATH)?;
+        Ok(serde_json::json!({
+            "type": "subscribe",
+            "product_ids": product_ids,
+            "channels": ["user"],
+            "signature": signature,
+            "key": self.key,
+            "passphrase": self.passphrase,
+            "timestamp": timestamp,
+        })
+        .to_string())
+    }
+
+    /// Frames moving a subscription from `current` to `updated` markets, a
+    /// signed subscription of the added ones and an unsubscription of the
+    /// dropped ones
+    fn update(&self, current: &[String], updated: &[String]) -> Result<Vec<String>, String> {
+        let added: Vec<String> = updated.iter().filter(|p| !current.contains(p)).cloned().collect();
+        let removed: Vec<&String> = current.iter().filter(|p| !updated.contains(p)).collect();
+
+        let mut frames = Vec::new();
+        if !added.is_empty() {
+            frames.push(self.subscribe(&added)?);
+        }
+        if !removed.is_empty() {
+            frames.push(unsubscribe(&removed));
+        }
+        Ok(frames)
+    }
+}
+
+fn unsubscribe(product_ids: &[&String]) -> String {
+    serde_json::json!({
+        "type": "unsubscribe",
+        "product_ids": product_ids,
+        "channels": ["user"],
+    })
+    .to_string()
+}
+
+/// Keeps a signed connection subscribed to the `user` channel, which carries
+/// our own orders and fills. The library can't sign subscriptions, so like
+/// `status` it runs on a connection of its own. Messages are handed over as
+/// they come since they're published as is. Changes of `products` are
+/// subscribed on the open connection.
+pub async fn run(
+    uri: String,
+    products: watch::Receiver<Vec<String>>,
+    credentials: Credentials,
+    backoff: Backoff,
+    tx: UnboundedSender<FeedEvent>,
+    shutdown: watch::Receiver<bool>,
+) {
+    let session = UserSession {
+        products,
+        credentials,
+        tx,
+        product_ids: Vec::new(),
+        fixed: false,
+    };
+    websocket::run(uri, session, backoff, shutdown).await;
+}
+
+struct UserSession {
+    products: watch::Receiver<Vec<String>>,
+    credentials: Credentials,
+    tx: UnboundedSender<FeedEvent>,
+    /// the markets subscribed on the open connection
+    product_ids: Vec<String>,
+    /// the markets never change once nothing can update them
+    fixed: bool,
+}
+
+impl Session for UserSession {
+    fn name(&self) -> &str {
+        "user"
+    }
+
+    fn subscriptions(&mut self) -> BoxFuture<'_, Option<Vec<String>>> {
+        Box::pin(async move {
+            loop {
+                if self.tx.is_closed() {
+                    return None;
+                }
+                let product_ids = self.products.borrow_and_update().clone();
+                if product_ids.is_empty() {
+                    if self.fixed || self.products.changed().await.is_err() {
+                        println!("no markets to subscribe to on the user channel");
+                        return None;
+                    }
+                    continue;
+                }
+
+                // signatures expire, sign every subscription anew
+                let subscribe = match self.credentials.subscribe(&product_ids) {
+                    Ok(subscribe) => subscribe,
+                    Err(e) => {
+                        println!("Failed signing the user subscription: {}", e);
+                        return None;
+                    }
+                };
+                println!("subscribing to user: {}", product_ids.join(","));
+                self.product_ids = product_ids;
+                return Some(vec![subscribe]);
+            }
+        })
+    }
+
+    fn updated(&mut self) -> BoxFuture<'_, Update> {
+        Box::pin(async move {
+            if self.fixed || self.products.changed().await.is_err() {
+                self.fixed = true;
+                return future::pending().await;
+            }
+
+            let updated = self.products.borrow_and_update().clone();
+            if updated.is_empty() {
+                return Update::Resubscribe;
+            }
+            match self.credentials.update(&self.product_ids, &updated) {
+                Ok(frames) => {
+                    println!("subscribing to user: {}", updated.join(","));
+                    self.product_ids = updated;
+                    Update::Send(frames)
+                }
+                Err(e) => {
+                    println!("Failed signing the user subscription: {}", e);
+                    Update::Stop
+                }
+            }
+        })
+    }
+
+    fn unsubscriptions(&self) -> Vec<String> {
+        let product_ids: Vec<&String> = self.product_ids.iter().collect();
+        vec![unsubscribe(&product_ids)]
+    }
+
+    fn received(&mut self, text: String) -> Flow {
+        let message: serde_json::Value = match serde_json::from_str(&text) {
+            Ok(message) => message,
+            Err(e) => {
+                println!("Failed parsing user message: {}: {}", e, text);
+                return Flow::Continue;
+            }
+        };
+
+        match message["type"].as_str() {
+            Some("subscriptions") => Flow::Continue,
+            // bad credentials or signature, coinbase closes the connection
+            Some("error") => {
+                println!("User channel error: {}", message);
+                Flow::Reconnect
+            }
+            _ => {
+                if self.tx.send(FeedEvent::User(message)).is_err() {
+                    return Flow::Stop;
+                }
+                Flow::Healthy
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+    use super::*;
+
+    const SECRET: &[u8] = b"not a real coinbase api secret";
+
+    fn credentials() -> Credentials {
+        Credentials {
+            key: "test-key".to_string(),
+            secret: BASE64.encode(SECRET),
+            passphrase: "test-passphrase".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribes_with_a_valid_signature() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("ws://{}", listener.local_addr().unwrap());
+
+        let (_markets, products) = watch::channel(vec!["BTC-USD".to_string()]);
+        let (stop, shutdown) = watch::channel(false);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let backoff = Backoff::new(Duration::from_millis(10), Duration::from_millis(100));
+        let feed = tokio::spawn(run(uri, products, credentials(), backoff, tx, shutdown));
+
+        let (tcp, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+        let subscribe = match ws.next().await {
+            Some(Ok(WsMessage::Text(subscribe))) => subscribe,
+            other => panic!("expected a subscribe frame, got {:?}", other),
+        };
+        let subscribe: serde_json::Value = serde_json::from_str(&subscribe).unwrap();
+
+        assert_eq!(subscribe["type"], "subscribe");
+        assert_eq!(subscribe["channels"], serde_json::json!(["user"]));
+        assert_eq!(subscribe["product_ids"], serde_json::json!(["BTC-USD"]));
+        assert_eq!(subscribe["key"], "test-key");
+        assert_eq!(subscribe["passphrase"], "test-passphrase");
+
+        // signed just now, with the secret coinbase would verify against
+        let timestamp = subscribe["timestamp"].as_str().unwrap();
+        let signed_at: i64 = timestamp.parse().unwrap();
+        assert!((Utc::now().timestamp() - signed_at).abs() < 30);
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET).unwrap();
+        mac.update(format!("{}GET/users/self/verify", timestamp).as_bytes());
+        let expected = BASE64.encode(mac.finalize().into_bytes());
+        assert_eq!(subscribe["signature"], expected.as_str());
+
+        // messages are handed over as they come
+        let done = r#"{"type":"done","order_id":"abc","product_id":"BTC-USD","reason":"filled"}"#;
+        ws.send(WsMessage::Text(done.to_string())).await.unwrap();
+        match rx.recv().await {
+            Some(FeedEvent::User(message)) => assert_eq!(message["order_id"], "abc"),
+            _ => panic!("expected the user message"),
+        }
+
+        // and the subscription is ended on shutdown
+        stop.send(true).unwrap();
+        let unsubscribe = match ws.next().await {
+            Some(Ok(WsMessage::Text(unsubscribe))) => unsubscribe,
+            other => panic!("expected an unsubscribe frame, got {:?}", other),
+        };
+        let unsubscribe: serde_json::Value = serde_json::from_str(&unsubscribe).unwrap();
+        assert_eq!(unsubscribe["type"], "unsubscribe");
+        assert_eq!(unsubscribe["product_ids"], serde_json::json!(["BTC-USD"]));
+        tokio::time::timeout(Duration::from_secs(5), feed).await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn signs_like_the_coinbase_docs() {
+        let signature = credentials().sign("1700000000", "GET", VERIFY_PATH).unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET).unwrap();
+        mac.update(b"1700000000GET/users/self/verify");
+        assert_eq!(signature, BASE64.encode(mac.finalize().into_bytes()));
+
+        let invalid = Credentials {
+            secret: "not base64!".to_string(),
+            ..credentials()
+        };
+        assert!(invalid.sign("1700000000", "GET", VERIFY_PATH).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use futures::future::BoxFuture;
+use futures::{SinkExt, StreamExt};
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+use crate::feed::Backoff;
+use crate::metrics;
+use crate::shutdown;
+
+/// What `run` does after a session handled a frame
+pub enum Flow {
+    /// keep reading, the connection works so the backoff starts over
+    Healthy,
+    /// keep reading
+    Continue,
+    /// reconnect after the backoff
+    Reconnect,
+    /// close the connection and stop, nobody takes the messages anymore
+    Stop,
+}
+
+/// What `run` does when the subscriptions of a session changed
+pub enum Update {
+    /// send these frames on the open connection
+    Send(Vec<String>),
+    /// close the connection and subscribe anew, without waiting
+    Resubscribe,
+    /// close the connection and stop
+    Stop,
+}
+
+/// The subscriptions of one websocket connection and what to do with its
+/// frames, kept connected by `run`
+pub trait Session: Send {
+    /// the name connections are logged under
+    fn name(&self) -> &str;
+
+    /// The frames subscribing a new connection, resolves once there's
+    /// something to subscribe to. None stops the session.
+    fn subscriptions(&mut self) -> BoxFuture<'_, Option<Vec<String>>>;
+
+    /// Resolves when the subscriptions of the open connection change, never
+    /// unless a session can change them
+    fn updated(&mut self) -> BoxFuture<'_, Update> {
+        Box::pin(futures::future::pending())
+    }
+
+    /// the frames ending the subscriptions, sent on shutdown
+    fn unsubscriptions(&self) -> Vec<String>;
+
+    fn received(&mut self, text: String) -> Flow;
+
+    /// Called after a connection was lost or closed to resubscribe
+    fn disconnected(&mut self) {}
+}
+
+/// How a connection ended
+enum Closed {
+    Lost,
+    Resubscribe,
+    Stop,
+}
+
+/// Keeps `session` connected to `url`, reconnecting with exponential
+/// backoff, until it stops or `shutdown` is set. On shutdown the session is
+/// unsubscribed and the websocket closed.
+pub async fn run<S: Session>(
+    url: String,
+    mut session: S,
+    mut backoff: Backoff,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let name = session.name().to_owned();
+
+    loop {
+        let subscriptions = tokio::select! {
+            subscriptions = session.subscriptions() => match subscriptions {
+                Some(subscriptions) => subscriptions,
+                None => return,
+            },
+            _ = shutdown::stopped(&mut shutdown) => return,
+        };
+
+        let closed = match tokio_tungstenite::connect_async(url.as_str()).await {
+            Ok((mut ws, _)) => {
+                let closed =
+                    connected(&mut ws, &mut session, subscriptions, &mut backoff, &mut shutdown)
+                        .await;
+                if let Closed::Stop = closed {
+                    for unsubscription in session.unsubscriptions() {
+                        let _ = ws.send(WsMessage::Text(unsubscription)).await;
+                    }
+                    let _ = ws.close(None).await;
+                    println!("unsubscribed from {}", name);
+                    return;
+                }
+                session.disconnected();
+                closed
+            }
+            Err(e) => {
+                println!("Failed connecting to {}: {}", url, e);
+                Closed::Lost
+            }
+        };
+
+        if let Closed::Lost = closed {
+            metrics::RECONNECTS.inc();
+            let delay = backoff.next_delay();
+            println!("{} websocket disconnected, reconnecting in {:?}", name, delay);
+            if !shutdown::sleep(delay, &mut shutdown).await {
+                return;
+            }
+        }
+    }
+}
+
+/// Subscribes an open connection and hands its frames to the session until
+/// it's lost, the session stops or `shutdown` is set
+async fn connected<W, S>(
+    ws: &mut W,
+    session: &mut S,
+    subscriptions: Vec<String>,
+    backoff: &mut Backoff,
+    shutdown: &mut watch::Receiver<bool>,
+) -> Closed
+where
+    W: futures::Sink<WsMessage, Error = tokio_tungstenite::tungstenite::Error>
+        + futures::Stream<Item = Result<WsMessage, tokio_tungstenite::tungstenite::Error>>
+        + Unpin,
+    S: Session,
+{
+    for subscription in subscriptions {
+        if let Err(e) = ws.send(WsMessage::Text(subscription)).await {
+            println!("Failed subscribing to {}: {}", session.name(), e);
+            return Closed::Lost;
+        }
+    }
+    println!("subscribed to {}", session.name());
+
+    loop {
+        let frame = tokio::select! {
+            frame = ws.next() => frame,
+            update = session.updated() => {
+                let frames = match update {
+                    Update::Send(frames) => frames,
+                    Update::Resubscribe => return Closed::Resubscribe,
+                    Update::Stop => return Closed::Stop,
+                };
+                for frame in frames {
+                    // the connection is gone, reconnect with the new subscriptions
+                    if let Err(e) = ws.send(WsMessage::Text(frame)).await {
+                        println!("Failed updating the {} subscription: {}", session.name(), e);
+                        return Closed::Lost;
+                    }
+                }
+                continue;
+            }
+            _ = shutdown::stopped(shutdown) => return Closed::Stop,
+        };
+
+        let text = match frame {
+            Some(Ok(WsMessage::Text(text))) => text,
+            Some(Ok(WsMessage::Close(_))) | None => return Closed::Lost,
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => {
+                println!("{} websocket error: {}", session.name(), e);
+                return Closed::Lost;
+            }
+        };
+
+        match session.received(text) {
+            Flow::Healthy => backoff.reset(),
+            Flow::Continue => {}
+            Flow::Reconnect => return Closed::Lost,
+            Flow::Stop => return Closed::Stop,
+        }
+    }
+}
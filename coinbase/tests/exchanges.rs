@@ -0,0 +1,164 @@
+//! The exchange adapters against frames recorded from each exchange: acks,
+//! heartbeats and status events parse to nothing, trades and quotes to
+//! their normalized records.
+
+use chrono::{DateTime, Utc};
+use coinbase::exchange::{Binance, Coinbase, ExchangeAdapter, Kraken};
+use feed_schema::{Normalized, Quote, Side, Trade};
+
+const COINBASE: &str = include_str!("fixtures/exchanges/coinbase.jsonl");
+const BINANCE: &str = include_str!("fixtures/exchanges/binance.jsonl");
+const KRAKEN: &str = include_str!("fixtures/exchanges/kraken.jsonl");
+
+fn time(time: &str) -> DateTime<Utc> {
+    time.parse().unwrap()
+}
+
+/// What each frame of a fixture parses to
+fn parse(adapter: &dyn ExchangeAdapter, fixture: &str) -> Vec<Vec<Normalized>> {
+    fixture
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|frame| adapter.parse(frame, time("2023-10-15T14:00:09Z")).unwrap())
+        .collect()
+}
+
+fn trade(exchange: &str, symbol: &str, price: f64, size: f64, side: Side) -> Trade {
+    Trade {
+        exchange: exchange.to_owned(),
+        symbol: symbol.to_owned(),
+        price,
+        size,
+        side,
+        ts: time("2023-10-15T14:00:00Z"),
+        id: None,
+    }
+}
+
+#[test]
+fn parses_coinbase_frames() {
+    let frames = parse(&Coinbase::new("ws://unused"), COINBASE);
+    let [ack, first_ticker, last_match, matched, ticker, heartbeat] = frames.as_slice() else {
+        panic!("unexpected frames: {:?}", frames);
+    };
+
+    assert!(ack.is_empty());
+    // sent on subscribe without a time, and the trade repeated on subscribe
+    assert!(first_ticker.is_empty());
+    assert!(last_match.is_empty());
+    assert!(heartbeat.is_empty());
+
+    // the side is the maker's, the taker bought from a seller
+    assert_eq!(
+        matched.as_slice(),
+        [Normalized::Trade(Trade {
+            ts: time("2023-10-15T14:00:00.25Z"),
+            id: Some("500000002".to_string()),
+            ..trade("coinbase", "BTC-USD", 27000.0, 0.1, Side::Sell)
+        })]
+    );
+    assert_eq!(
+        ticker.as_slice(),
+        [Normalized::Quote(Quote {
+            exchange: "coinbase".to_string(),
+            symbol: "BTC-USD".to_string(),
+            bid: 26999.99,
+            bid_size: Some(0.5),
+            ask: 27000.01,
+            ask_size: Some(1.2),
+            ts: time("2023-10-15T14:00:00.25Z"),
+        })]
+    );
+}
+
+#[test]
+fn parses_binance_frames() {
+    let frames = parse(&Binance::new("ws://unused"), BINANCE);
+    let [ack, maker_buy, maker_sell, book_ticker] = frames.as_slice() else {
+        panic!("unexpected frames: {:?}", frames);
+    };
+
+    assert!(ack.is_empty());
+    assert_eq!(
+        maker_buy.as_slice(),
+        [Normalized::Trade(Trade {
+            ts: time("2023-10-15T14:00:05Z"),
+            id: Some("3212345678".to_string()),
+            ..trade("binance", "BTCUSDT", 27001.5, 0.0042, Side::Sell)
+        })]
+    );
+    assert_eq!(
+        maker_sell.as_slice(),
+        [Normalized::Trade(Trade {
+            ts: time("2023-10-15T14:00:05.1Z"),
+            id: Some("3212345679".to_string()),
+            ..trade("binance", "BTCUSDT", 27002.0, 0.01, Side::Buy)
+        })]
+    );
+
+    // book tickers carry no time, they're stamped when received
+    assert_eq!(
+        book_ticker.as_slice(),
+        [Normalized::Quote(Quote {
+            exchange: "binance".to_string(),
+            symbol: "BTCUSDT".to_string(),
+            bid: 27001.99,
+            bid_size: Some(3.21),
+            ask: 27002.0,
+            ask_size: Some(0.66),
+            ts: time("2023-10-15T14:00:09Z"),
+        })]
+    );
+}
+
+#[test]
+fn parses_kraken_frames() {
+    let frames = parse(&Kraken::new("ws://unused"), KRAKEN);
+    let [status, trade_ack, spread_ack, heartbeat, trades, spread] = frames.as_slice() else {
+        panic!("unexpected frames: {:?}", frames);
+    };
+
+    assert!(status.is_empty());
+    assert!(trade_ack.is_empty());
+    assert!(spread_ack.is_empty());
+    assert!(heartbeat.is_empty());
+
+    // kraken has no trade ids
+    let ts = time("2023-10-15T14:00:05.321597Z");
+    assert_eq!(
+        trades.as_slice(),
+        [
+            Normalized::Trade(Trade {
+                ts,
+                ..trade("kraken", "XBT/USD", 27003.1, 0.15850568, Side::Sell)
+            }),
+            Normalized::Trade(Trade {
+                ts,
+                ..trade("kraken", "XBT/USD", 27003.2, 0.02455, Side::Buy)
+            }),
+        ]
+    );
+    assert_eq!(
+        spread.as_slice(),
+        [Normalized::Quote(Quote {
+            exchange: "kraken".to_string(),
+            symbol: "XBT/USD".to_string(),
+            bid: 27003.1,
+            bid_size: Some(1.01234567),
+            ask: 27003.2,
+            ask_size: Some(0.98765432),
+            ts: time("2023-10-15T14:00:05.545897Z"),
+        })]
+    );
+}
+
+#[test]
+fn rejects_malformed_trades() {
+    let kraken = Kraken::new("ws://unused");
+    let bad_side = r#"[337,[["27003.1","0.1","1697378405.321597","x","l",""]],"trade","XBT/USD"]"#;
+    assert!(kraken.parse(bad_side, Utc::now()).is_err());
+
+    let binance = Binance::new("ws://unused");
+    let bad_price = r#"{"e":"trade","s":"BTCUSDT","t":1,"p":"abc","q":"1","T":1697378405000}"#;
+    assert!(binance.parse(bad_price, Utc::now()).is_err());
+}
@@ -0,0 +1,22 @@
+use apache_avro::{from_avro_datum, from_value, to_avro_datum, to_value, Schema};
+
+use crate::wire::WireRecord;
+use crate::FeedRecord;
+
+lazy_static::lazy_static! {
+    static ref SCHEMA: Schema =
+        Schema::parse_str(include_str!("../schemas/v1/feed.avsc")).expect("invalid avro schema");
+}
+
+pub fn encode(record: &FeedRecord) -> Result<Vec<u8>, String> {
+    let value = to_value(WireRecord::from(record)).map_err(|e| e.to_string())?;
+    // resolve so the optional fields pick their union branch
+    let value = value.resolve(&SCHEMA).map_err(|e| e.to_string())?;
+    to_avro_datum(&SCHEMA, value).map_err(|e| e.to_string())
+}
+
+pub fn decode(mut data: &[u8]) -> Result<FeedRecord, String> {
+    let value = from_avro_datum(&SCHEMA, &mut data, None).map_err(|e| e.to_string())?;
+    let record: WireRecord = from_value(&value).map_err(|e| e.to_string())?;
+    record.try_into()
+}
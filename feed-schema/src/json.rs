@@ -0,0 +1,30 @@
+use serde::Deserialize;
+use serde_json::value::RawValue;
+
+use crate::{FeedRecord, Metadata, Payload};
+
+#[derive(Deserialize)]
+struct Envelope<'a> {
+    meta: Metadata,
+    #[serde(borrow)]
+    data: &'a RawValue,
+}
+
+/// `{"meta":{...},"data":<message>}`, the message is embedded as is
+pub fn encode(record: &FeedRecord) -> Result<Vec<u8>, String> {
+    let meta = serde_json::to_string(&record.meta).map_err(|e| e.to_string())?;
+    let data = match &record.payload {
+        Payload::Ticker(ticker) => serde_json::to_string(ticker).map_err(|e| e.to_string())?,
+        Payload::Json(json) => json.clone(),
+    };
+    Ok(format!(r#"{{"meta":{},"data":{}}}"#, meta, data).into_bytes())
+}
+
+pub fn decode(data: &[u8]) -> Result<FeedRecord, String> {
+    let envelope: Envelope = serde_json::from_slice(data).map_err(|e| e.to_string())?;
+    let payload = Payload::from_json(&envelope.meta.channel, envelope.data.get());
+    Ok(FeedRecord {
+        meta: envelope.meta,
+        payload,
+    })
+}
@@ -0,0 +1,151 @@
+//! The records the coinbase producer writes to kafka and how they're encoded,
+//! shared by the producer and its consumers so both agree on the shape.
+//!
+//! The binary schemas live in `schemas/v<version>/`. Trades and quotes
+//! normalized across exchanges are in [`Normalized`].
+
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+mod avro;
+mod json;
+mod msgpack;
+mod normalized;
+mod proto;
+mod wire;
+
+pub use normalized::{Normalized, Quote, Trade};
+
+/// Bumped whenever the records change shape, a new version gets new schema files
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// How records are encoded on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// `{"meta":{...},"data":<message>}`
+    Json,
+    MsgPack,
+    /// `schemas/v1/feed.proto`
+    Protobuf,
+    /// `schemas/v1/feed.avsc`, single datums without a container header
+    Avro,
+}
+
+impl Format {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::MsgPack => "msgpack",
+            Format::Protobuf => "protobuf",
+            Format::Avro => "avro",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "msgpack" => Ok(Format::MsgPack),
+            "protobuf" | "proto" => Ok(Format::Protobuf),
+            "avro" => Ok(Format::Avro),
+            _ => Err(format!(
+                "unknown format '{}', expected json, msgpack, protobuf or avro",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Ingest metadata sent along with every record
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    pub product: String,
+    pub channel: String,
+    /// exchange sequence, for messages that carry one
+    pub sequence: Option<u64>,
+    /// when the exchange says the message happened
+    pub exchange_time: Option<DateTime<Utc>>,
+    /// when the producer received it
+    pub received: DateTime<Utc>,
+    /// host the producer runs on
+    pub producer: String,
+    pub schema_version: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+/// A ticker with a trade, the json field names are the ones coinbase uses
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ticker {
+    pub product_id: String,
+    pub sequence: u64,
+    pub trade_id: u64,
+    pub time: DateTime<Utc>,
+    pub price: f64,
+    pub side: Side,
+    pub last_size: f64,
+    pub best_bid: f64,
+    pub best_ask: f64,
+}
+
+/// What a record carries
+#[derive(Debug, Clone, PartialEq)]
+pub enum Payload {
+    Ticker(Ticker),
+    /// any other message or marker, as json
+    Json(String),
+}
+
+impl Payload {
+    /// Types a json message when it's a ticker with a trade
+    pub fn from_json(channel: &str, json: &str) -> Self {
+        if channel == "ticker" {
+            if let Ok(ticker) = serde_json::from_str::<Ticker>(json) {
+                return Payload::Ticker(ticker);
+            }
+        }
+        Payload::Json(json.to_owned())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedRecord {
+    pub meta: Metadata,
+    pub payload: Payload,
+}
+
+/// Encodes a record as `format`
+pub fn encode(format: Format, record: &FeedRecord) -> Result<Vec<u8>, String> {
+    match format {
+        Format::Json => json::encode(record),
+        Format::MsgPack => msgpack::encode(record),
+        Format::Protobuf => Ok(proto::encode(record)),
+        Format::Avro => avro::encode(record),
+    }
+}
+
+/// Decodes a record encoded as `format`
+pub fn decode(format: Format, data: &[u8]) -> Result<FeedRecord, String> {
+    match format {
+        Format::Json => json::decode(data),
+        Format::MsgPack => msgpack::decode(data),
+        Format::Protobuf => proto::decode(data),
+        Format::Avro => avro::decode(data),
+    }
+}
@@ -0,0 +1,12 @@
+use crate::wire::WireRecord;
+use crate::FeedRecord;
+
+/// Named fields so a consumer built against an older version can skip new ones
+pub fn encode(record: &FeedRecord) -> Result<Vec<u8>, String> {
+    rmp_serde::to_vec_named(&WireRecord::from(record)).map_err(|e| e.to_string())
+}
+
+pub fn decode(data: &[u8]) -> Result<FeedRecord, String> {
+    let record: WireRecord = rmp_serde::from_slice(data).map_err(|e| e.to_string())?;
+    record.try_into()
+}
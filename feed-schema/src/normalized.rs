@@ -0,0 +1,64 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::Side;
+
+/// A trade on any exchange
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Trade {
+    pub exchange: String,
+    /// the exchange's own spelling e.g. 'BTC-USD', 'BTCUSDT' or 'XBT/USD'
+    pub symbol: String,
+    pub price: f64,
+    pub size: f64,
+    /// the taker's side
+    pub side: Side,
+    pub ts: DateTime<Utc>,
+    /// the exchange's trade id, kraken has none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+}
+
+/// Best bid and ask on any exchange, sizes when the exchange sends them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Quote {
+    pub exchange: String,
+    pub symbol: String,
+    pub bid: f64,
+    pub bid_size: Option<f64>,
+    pub ask: f64,
+    pub ask_size: Option<f64>,
+    pub ts: DateTime<Utc>,
+}
+
+/// What exchange adapters emit, published as json with a `type` of `trade`
+/// or `quote`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Normalized {
+    Trade(Trade),
+    Quote(Quote),
+}
+
+impl Normalized {
+    pub fn exchange(&self) -> &str {
+        match self {
+            Normalized::Trade(trade) => &trade.exchange,
+            Normalized::Quote(quote) => &quote.exchange,
+        }
+    }
+
+    pub fn symbol(&self) -> &str {
+        match self {
+            Normalized::Trade(trade) => &trade.symbol,
+            Normalized::Quote(quote) => &quote.symbol,
+        }
+    }
+
+    pub fn ts(&self) -> DateTime<Utc> {
+        match self {
+            Normalized::Trade(trade) => trade.ts,
+            Normalized::Quote(quote) => quote.ts,
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use prost::Message;
+
+use crate::wire::{from_micros, to_micros};
+use crate::{FeedRecord, Payload, Side, Ticker};
+
+// Hand written `schemas/v1/feed.proto`, keep the tags in sync with it
+
+#[derive(Clone, PartialEq, Message)]
+struct ProtoMetadata {
+    #[prost(string, tag = "1")]
+    product: String,
+    #[prost(string, tag = "2")]
+    channel: String,
+    #[prost(uint64, optional, tag = "3")]
+    sequence: Option<u64>,
+    #[prost(int64, optional, tag = "4")]
+    exchange_time: Option<i64>,
+    #[prost(int64, tag = "5")]
+    received: i64,
+    #[prost(string, tag = "6")]
+    producer: String,
+    #[prost(uint32, tag = "7")]
+    schema_version: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+enum ProtoSide {
+    Unspecified = 0,
+    Buy = 1,
+    Sell = 2,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ProtoTicker {
+    #[prost(string, tag = "1")]
+    product_id: String,
+    #[prost(uint64, tag = "2")]
+    sequence: u64,
+    #[prost(uint64, tag = "3")]
+    trade_id: u64,
+    #[prost(int64, tag = "4")]
+    time: i64,
+    #[prost(double, tag = "5")]
+    price: f64,
+    #[prost(enumeration = "ProtoSide", tag = "6")]
+    side: i32,
+    #[prost(double, tag = "7")]
+    last_size: f64,
+    #[prost(double, tag = "8")]
+    best_bid: f64,
+    #[prost(double, tag = "9")]
+    best_ask: f64,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum ProtoData {
+    #[prost(message, tag = "2")]
+    Ticker(ProtoTicker),
+    #[prost(string, tag = "3")]
+    Json(String),
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ProtoRecord {
+    #[prost(message, optional, tag = "1")]
+    meta: Option<ProtoMetadata>,
+    #[prost(oneof = "ProtoData", tags = "2, 3")]
+    data: Option<ProtoData>,
+}
+
+pub fn encode(record: &FeedRecord) -> Vec<u8> {
+    let meta = &record.meta;
+    let data = match &record.payload {
+        Payload::Ticker(t) => ProtoData::Ticker(ProtoTicker {
+            product_id: t.product_id.clone(),
+            sequence: t.sequence,
+            trade_id: t.trade_id,
+            time: to_micros(&t.time),
+            price: t.price,
+            side: match t.side {
+                Side::Buy => ProtoSide::Buy,
+                Side::Sell => ProtoSide::Sell,
+            } as i32,
+            last_size: t.last_size,
+            best_bid: t.best_bid,
+            best_ask: t.best_ask,
+        }),
+        Payload::Json(json) => ProtoData::Json(json.clone()),
+    };
+
+    ProtoRecord {
+        meta: Some(ProtoMetadata {
+            product: meta.product.clone(),
+            channel: meta.channel.clone(),
+            sequence: meta.sequence,
+            exchange_time: meta.exchange_time.as_ref().map(to_micros),
+            received: to_micros(&meta.received),
+            producer: meta.producer.clone(),
+            schema_version: meta.schema_version,
+        }),
+        data: Some(data),
+    }
+    .encode_to_vec()
+}
+
+pub fn decode(data: &[u8]) -> Result<FeedRecord, String> {
+    let record = ProtoRecord::decode(data).map_err(|e| e.to_string())?;
+    let meta = record.meta.ok_or("record has no metadata")?;
+
+    let payload = match record.data.ok_or("record has no data")? {
+        ProtoData::Ticker(t) => Payload::Ticker(Ticker {
+            side: match ProtoSide::try_from(t.side) {
+                Ok(ProtoSide::Buy) => Side::Buy,
+                Ok(ProtoSide::Sell) => Side::Sell,
+                _ => return Err(format!("unknown side {}", t.side)),
+            },
+            product_id: t.product_id,
+            sequence: t.sequence,
+            trade_id: t.trade_id,
+            time: from_micros(t.time)?,
+            price: t.price,
+            last_size: t.last_size,
+            best_bid: t.best_bid,
+            best_ask: t.best_ask,
+        }),
+        ProtoData::Json(json) => Payload::Json(json),
+    };
+
+    Ok(FeedRecord {
+        meta: crate::Metadata {
+            product: meta.product,
+            channel: meta.channel,
+            sequence: meta.sequence,
+            exchange_time: meta.exchange_time.map(from_micros).transpose()?,
+            received: from_micros(meta.received)?,
+            producer: meta.producer,
+            schema_version: meta.schema_version,
+        },
+        payload,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: &str = include_str!("../schemas/v1/feed.proto");
+
+    /// The tag and wire type of `field` in `message` of feed.proto
+    fn declared(message: &str, field: &str) -> (u64, u64) {
+        let body = SCHEMA
+            .split(&format!("message {} {{", message))
+            .nth(1)
+            .unwrap_or_else(|| panic!("no message {} in feed.proto", message));
+        body.lines()
+            .map(|line| line.trim().trim_end_matches(';'))
+            .take_while(|line| *line != "}")
+            .find_map(|line| {
+                let (declaration, tag) = line.split_once(" = ")?;
+                let mut words = declaration.split_whitespace().rev();
+                if words.next()? != field {
+                    return None;
+                }
+                let wire_type = match words.next()? {
+                    "double" => 1,
+                    "string" | "Metadata" | "Ticker" => 2,
+                    _ => 0,
+                };
+                Some((tag.parse().unwrap(), wire_type))
+            })
+            .unwrap_or_else(|| panic!("no field {}.{} in feed.proto", message, field))
+    }
+
+    /// A field name and how to set it
+    type Field<M> = (&'static str, fn(&mut M));
+
+    /// Sets each field alone and checks that it encodes with the tag and
+    /// wire type feed.proto declares
+    fn check<M: Message + Default>(message: &str, fields: &[Field<M>]) {
+        for (field, set) in fields {
+            let mut value = M::default();
+            set(&mut value);
+            let bytes = value.encode_to_vec();
+            let key = prost::encoding::decode_varint(&mut bytes.as_slice()).unwrap();
+            let encoded = (key >> 3, key & 7);
+            assert_eq!(encoded, declared(message, field), "{}.{}", message, field);
+        }
+    }
+
+    #[test]
+    fn matches_the_schema() {
+        check::<ProtoMetadata>(
+            "Metadata",
+            &[
+                ("product", |m| m.product = "a".into()),
+                ("channel", |m| m.channel = "a".into()),
+                ("sequence", |m| m.sequence = Some(1)),
+                ("exchange_time", |m| m.exchange_time = Some(1)),
+                ("received", |m| m.received = 1),
+                ("producer", |m| m.producer = "a".into()),
+                ("schema_version", |m| m.schema_version = 1),
+            ],
+        );
+        check::<ProtoTicker>(
+            "Ticker",
+            &[
+                ("product_id", |t| t.product_id = "a".into()),
+                ("sequence", |t| t.sequence = 1),
+                ("trade_id", |t| t.trade_id = 1),
+                ("time", |t| t.time = 1),
+                ("price", |t| t.price = 1.0),
+                ("side", |t| t.side = ProtoSide::Buy as i32),
+                ("last_size", |t| t.last_size = 1.0),
+                ("best_bid", |t| t.best_bid = 1.0),
+                ("best_ask", |t| t.best_ask = 1.0),
+            ],
+        );
+        check::<ProtoRecord>(
+            "Record",
+            &[
+                ("meta", |r| r.meta = Some(ProtoMetadata::default())),
+                ("ticker", |r| {
+                    r.data = Some(ProtoData::Ticker(ProtoTicker::default()))
+                }),
+                ("json", |r| r.data = Some(ProtoData::Json("{}".into()))),
+            ],
+        );
+    }
+}
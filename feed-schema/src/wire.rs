@@ -0,0 +1,136 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{FeedRecord, Metadata, Payload, Side, Ticker};
+
+// The flat shape of `schemas/v1/feed.avsc`, shared with msgpack. Timestamps
+// are microseconds since the unix epoch and the side is a string.
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WireRecord {
+    pub meta: WireMetadata,
+    pub ticker: Option<WireTicker>,
+    pub json: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WireMetadata {
+    pub product: String,
+    pub channel: String,
+    pub sequence: Option<i64>,
+    pub exchange_time: Option<i64>,
+    pub received: i64,
+    pub producer: String,
+    pub schema_version: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WireTicker {
+    pub product_id: String,
+    pub sequence: i64,
+    pub trade_id: i64,
+    pub time: i64,
+    pub price: f64,
+    pub side: String,
+    pub last_size: f64,
+    pub best_bid: f64,
+    pub best_ask: f64,
+}
+
+pub fn to_micros(time: &DateTime<Utc>) -> i64 {
+    time.timestamp_micros()
+}
+
+pub fn from_micros(micros: i64) -> Result<DateTime<Utc>, String> {
+    let nanos = (micros.rem_euclid(1_000_000) * 1_000) as u32;
+    DateTime::<Utc>::from_timestamp(micros.div_euclid(1_000_000), nanos)
+        .ok_or_else(|| format!("timestamp out of range: {}", micros))
+}
+
+pub fn side_name(side: Side) -> &'static str {
+    match side {
+        Side::Buy => "buy",
+        Side::Sell => "sell",
+    }
+}
+
+pub fn parse_side(side: &str) -> Result<Side, String> {
+    match side {
+        "buy" => Ok(Side::Buy),
+        "sell" => Ok(Side::Sell),
+        _ => Err(format!("unknown side '{}'", side)),
+    }
+}
+
+impl From<&FeedRecord> for WireRecord {
+    fn from(record: &FeedRecord) -> Self {
+        let meta = &record.meta;
+        let (ticker, json) = match &record.payload {
+            Payload::Ticker(t) => (
+                Some(WireTicker {
+                    product_id: t.product_id.clone(),
+                    sequence: t.sequence as i64,
+                    trade_id: t.trade_id as i64,
+                    time: to_micros(&t.time),
+                    price: t.price,
+                    side: side_name(t.side).to_owned(),
+                    last_size: t.last_size,
+                    best_bid: t.best_bid,
+                    best_ask: t.best_ask,
+                }),
+                None,
+            ),
+            Payload::Json(json) => (None, Some(json.clone())),
+        };
+
+        WireRecord {
+            meta: WireMetadata {
+                product: meta.product.clone(),
+                channel: meta.channel.clone(),
+                sequence: meta.sequence.map(|s| s as i64),
+                exchange_time: meta.exchange_time.as_ref().map(to_micros),
+                received: to_micros(&meta.received),
+                producer: meta.producer.clone(),
+                schema_version: meta.schema_version as i32,
+            },
+            ticker,
+            json,
+        }
+    }
+}
+
+impl TryFrom<WireRecord> for FeedRecord {
+    type Error = String;
+
+    fn try_from(record: WireRecord) -> Result<Self, Self::Error> {
+        let meta = record.meta;
+        let payload = match (record.ticker, record.json) {
+            (Some(t), _) => Payload::Ticker(Ticker {
+                product_id: t.product_id,
+                sequence: t.sequence as u64,
+                trade_id: t.trade_id as u64,
+                time: from_micros(t.time)?,
+                price: t.price,
+                side: parse_side(&t.side)?,
+                last_size: t.last_size,
+                best_bid: t.best_bid,
+                best_ask: t.best_ask,
+            }),
+            (None, Some(json)) => Payload::Json(json),
+            (None, None) => return Err("record has no data".to_string()),
+        };
+
+        Ok(FeedRecord {
+            meta: Metadata {
+                product: meta.product,
+                channel: meta.channel,
+                sequence: meta.sequence.map(|s| s as u64),
+                exchange_time: meta.exchange_time.map(from_micros).transpose()?,
+                received: from_micros(meta.received)?,
+                producer: meta.producer,
+                schema_version: meta.schema_version as u32,
+            },
+            payload,
+        })
+    }
+}
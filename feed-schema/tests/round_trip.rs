@@ -0,0 +1,87 @@
+//! Every format decodes what it encoded, for both kinds of payload and with
+//! and without the optional metadata.
+
+use chrono::{DateTime, Utc};
+use feed_schema::{decode, encode, FeedRecord, Format, Metadata, Payload, Side, Ticker};
+
+const FORMATS: [Format; 4] = [
+    Format::Json,
+    Format::MsgPack,
+    Format::Protobuf,
+    Format::Avro,
+];
+
+fn time(time: &str) -> DateTime<Utc> {
+    time.parse().unwrap()
+}
+
+fn meta(channel: &str, sequence: Option<u64>, exchange_time: Option<DateTime<Utc>>) -> Metadata {
+    Metadata {
+        product: "BTC-USD".to_string(),
+        channel: channel.to_string(),
+        sequence,
+        exchange_time,
+        received: time("2023-10-15T14:00:00.251234Z"),
+        producer: "host-1".to_string(),
+        schema_version: feed_schema::SCHEMA_VERSION,
+    }
+}
+
+fn ticker_record() -> FeedRecord {
+    let exchange_time = time("2023-10-15T14:00:00.250000Z");
+    FeedRecord {
+        meta: meta("ticker", Some(69180915843), Some(exchange_time)),
+        payload: Payload::Ticker(Ticker {
+            product_id: "BTC-USD".to_string(),
+            sequence: 69180915843,
+            trade_id: 560383172,
+            time: exchange_time,
+            price: 27001.5,
+            side: Side::Sell,
+            last_size: 0.0042,
+            best_bid: 27001.49,
+            best_ask: 27001.5,
+        }),
+    }
+}
+
+fn json_record() -> FeedRecord {
+    let gap = r#"{"type":"gap","product_id":"BTC-USD","last_sequence":100,"sequence":180}"#;
+    FeedRecord {
+        meta: meta("matches", None, None),
+        payload: Payload::Json(gap.to_string()),
+    }
+}
+
+fn round_trip(format: Format, record: &FeedRecord) -> FeedRecord {
+    let encoded = encode(format, record).unwrap_or_else(|e| panic!("encoding {}: {}", format, e));
+    decode(format, &encoded).unwrap_or_else(|e| panic!("decoding {}: {}", format, e))
+}
+
+#[test]
+fn tickers_round_trip() {
+    let record = ticker_record();
+    for format in FORMATS {
+        assert_eq!(round_trip(format, &record), record, "{}", format);
+    }
+}
+
+#[test]
+fn json_payloads_round_trip() {
+    let record = json_record();
+    for format in FORMATS {
+        assert_eq!(round_trip(format, &record), record, "{}", format);
+    }
+}
+
+#[test]
+fn buys_and_optional_metadata_round_trip() {
+    let mut record = ticker_record();
+    record.meta = meta("ticker", Some(0), None);
+    if let Payload::Ticker(ticker) = &mut record.payload {
+        ticker.side = Side::Buy;
+    }
+    for format in FORMATS {
+        assert_eq!(round_trip(format, &record), record, "{}", format);
+    }
+}
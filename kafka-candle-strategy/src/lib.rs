@@ -0,0 +1,210 @@
+//! Candles built from the coinbase producer's feed records. The binary
+//! consumes them from kafka, anything else holding decoded records (like the
+//! pipeline tests) can feed [`Candles`] directly.
+
+use bigdecimal::{BigDecimal, FromPrimitive, RoundingMode};
+use chrono::{DateTime, Utc};
+use feed_schema::{FeedRecord, Metadata, Payload, Side, Ticker};
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+
+// 60 seconds for candle interval
+pub static CANDLE_INTERVAL: i64 = 60;
+
+#[derive(Debug, Clone)]
+pub struct Candle {
+    // OHLC
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+
+    // track candle volume and count
+    pub buy_count: usize,
+    pub buy_volume: BigDecimal,
+    pub sell_count: usize,
+    pub sell_volume: BigDecimal,
+
+    pub time: DateTime<Utc>,
+
+    // track trade ids
+    pub trades: Vec<u64>,
+}
+
+impl Candle {
+    fn new(time: DateTime<Utc>, price: f64) -> Self {
+        Self {
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            buy_count:0,
+            buy_volume: BigDecimal::from_f64(0.0).unwrap(),
+            sell_count:0,
+            sell_volume: BigDecimal::from_f64(0.0).unwrap(),
+            time,
+            trades: Vec::new(),
+        }
+    }
+}
+
+impl std::fmt::Display for Candle {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let total_volume = self.buy_volume.clone() + self.sell_volume.clone();
+        write!(
+            f,
+            "{} -- O: {:.8} H: {:.8} L: {:.8} C: {:.8} BV: {:.8} SV: {:.8} TV: {} BC: {} SC: {}",
+            self.time,
+            self.open,
+            self.high,
+            self.low,
+            self.close,
+            self.buy_volume,
+            self.sell_volume,
+            total_volume.with_scale_round(8, RoundingMode::HalfUp),
+            self.buy_count,
+            self.sell_count,
+        )
+    }
+}
+
+/// The candles of every product on a topic
+#[derive(Debug, Default)]
+pub struct Candles {
+    // Create a BTreeMap per product to store OHLC candles, where the key is the candle start time
+    // we use a BTreeMap because it keeps the keys sorted
+    product_candles: HashMap<String, BTreeMap<DateTime<Utc>, Candle>>,
+
+    // last exchange sequence per product, to spot records arriving out of order
+    last_sequences: HashMap<String, u64>,
+}
+
+impl Candles {
+    /// The candles of a product by start time
+    pub fn product(&self, product_id: &str) -> Option<&BTreeMap<DateTime<Utc>, Candle>> {
+        self.product_candles.get(product_id)
+    }
+
+    /// Adds the trade of a record to its candle. Returns the previous candle
+    /// of the product once the trade starts a new one, as it should be finished
+    pub fn apply(&mut self, record: FeedRecord) -> Option<(String, &Candle)> {
+        check_metadata(&record.meta, &mut self.last_sequences);
+
+        let trade = match record.payload {
+            Payload::Ticker(ticker) => ticker,
+            Payload::Json(json) => {
+                let value: serde_json::Value = serde_json::from_str(&json).unwrap_or_default();
+                // the producer publishes a gap marker when feed data was lost
+                if value["type"] == "gap" {
+                    warn!("feed gap: {}", value);
+                }
+                // and the trades it recovered afterwards
+                backfilled_trade(&value)?
+            }
+        };
+
+        let Ticker {
+            trade_id,
+            sequence: _,
+            time,
+            product_id,
+            price,
+            side,
+            last_size,
+            best_bid: _,
+            best_ask: _,
+        } = trade;
+
+        // Get the candle start time based on the candle_interval
+        let candle_start_time = time.timestamp() / CANDLE_INTERVAL * CANDLE_INTERVAL;
+
+        // Get or insert the OHLC candle for the current interval
+        let dt = DateTime::<Utc>::from_timestamp(candle_start_time, 0).expect("invalid timestamp");
+
+        // the topic may carry several markets, keep their candles apart
+        let ohlc_candles = self.product_candles.entry(product_id.clone()).or_default();
+        let len = ohlc_candles.len();
+
+        // scope to limit mutable borrow
+        {
+            let candle_entry = ohlc_candles.entry(dt).or_insert(Candle::new(dt, price));
+
+            // skip dupe trade ids
+            if candle_entry.trades.contains(&trade_id) {
+                return None;
+            }
+
+            candle_entry.trades.push(trade_id);
+
+            // Update OHLC values
+            if price > candle_entry.high {
+                candle_entry.high = price;
+            }
+            if price < candle_entry.low {
+                candle_entry.low = price;
+            }
+
+            candle_entry.close = price;
+
+            // track side volumes and counts
+            if side == Side::Buy {
+                candle_entry.buy_count += 1;
+                candle_entry.buy_volume += BigDecimal::from_f64(last_size).unwrap();
+            } else {
+                candle_entry.sell_count += 1;
+                candle_entry.sell_volume += BigDecimal::from_f64(last_size).unwrap();
+            }
+        }
+
+        // if we're working on a new candle then hand back the previous candle
+        if len < ohlc_candles.len() && len > 0 {
+            let sorted: Vec<&DateTime<Utc>> = ohlc_candles.keys().collect();
+            let previous_candle = ohlc_candles.get(sorted[sorted.len() - 2]).unwrap();
+            return Some((product_id, previous_candle));
+        }
+        None
+    }
+}
+
+/// A trade the producer recovered from the REST api after a gap, as a ticker.
+/// They carry no sequence or quotes, and their side is the maker's while a
+/// ticker's is the taker's
+fn backfilled_trade(value: &serde_json::Value) -> Option<Ticker> {
+    if value["backfilled"] != true {
+        return None;
+    }
+
+    let side = match value["side"].as_str()? {
+        "buy" => Side::Sell,
+        "sell" => Side::Buy,
+        _ => return None,
+    };
+
+    Some(Ticker {
+        product_id: value["product_id"].as_str()?.to_owned(),
+        sequence: 0,
+        trade_id: value["trade_id"].as_u64()?,
+        time: value["time"].as_str()?.parse().ok()?,
+        price: value["price"].as_f64()?,
+        side,
+        last_size: value["size"].as_f64()?,
+        best_bid: 0.0,
+        best_ask: 0.0,
+    })
+}
+
+/// Logs the ingest latency of a record and warns when its sequence is
+/// older than one already seen for the product
+fn check_metadata(meta: &Metadata, last_sequences: &mut HashMap<String, u64>) {
+    let lag = Utc::now() - meta.received;
+    log::debug!("{} ingest lag: {}ms", meta.product, lag.num_milliseconds());
+
+    if let Some(sequence) = meta.sequence {
+        let last = last_sequences.entry(meta.product.clone()).or_insert(sequence);
+        if sequence < *last {
+            warn!("{}: sequence {} arrived after {}", meta.product, sequence, last);
+        } else {
+            *last = sequence;
+        }
+    }
+}
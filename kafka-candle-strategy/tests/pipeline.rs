@@ -0,0 +1,239 @@
+//! The producer and the candle consumer wired together without a network: a
+//! mock coinbase websocket replays the fixture tickers to the `coinbase`
+//! feed, its records go through an in-memory stand-in for kafka, and the
+//! candles built from them are checked.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use bigdecimal::{BigDecimal, RoundingMode};
+use chrono::{DateTime, Utc};
+use coinbase::{Backoff, Channel, ChannelSink, FeedPublisher, Format, PublishedRecord};
+use futures::{SinkExt, StreamExt};
+use kafka_candle_strategy::{Candle, Candles};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Frames sent on the first connection, which is then closed
+const FIRST_CONNECTION: &str = include_str!("fixtures/tickers-1.jsonl");
+/// Frames sent after the feed reconnects, starting with two sent before
+const SECOND_CONNECTION: &str = include_str!("fixtures/tickers-2.jsonl");
+
+const LAST_TRADE_ID: u64 = 6;
+const UNIQUE_TRADES: usize = 9;
+
+/// Serves one websocket connection per list of frames, in order. Every
+/// connection but the last is closed once its frames are sent. Returns the
+/// subscribe frame of each connection.
+fn mock_coinbase(
+    listener: TcpListener,
+    connections: Vec<Vec<String>>,
+) -> JoinHandle<Vec<String>> {
+    tokio::spawn(async move {
+        let mut subscriptions = Vec::new();
+        let count = connections.len();
+        for (i, frames) in connections.into_iter().enumerate() {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+
+            match ws.next().await {
+                Some(Ok(Message::Text(subscribe))) => subscriptions.push(subscribe),
+                other => panic!("expected a subscribe frame, got {:?}", other),
+            }
+            for frame in frames {
+                ws.send(Message::Text(frame)).await.unwrap();
+            }
+
+            if i + 1 < count {
+                ws.close(None).await.unwrap();
+            } else {
+                // until the feed closes on stop
+                while let Some(Ok(_)) = ws.next().await {}
+            }
+        }
+        subscriptions
+    })
+}
+
+fn frames(fixture: &str) -> Vec<String> {
+    fixture.lines().filter(|line| !line.is_empty()).map(str::to_owned).collect()
+}
+
+/// Stands in for kafka: the encoded values of every topic in the order they
+/// were produced
+#[derive(Default)]
+struct MemoryBroker {
+    topics: HashMap<String, Vec<Vec<u8>>>,
+}
+
+impl MemoryBroker {
+    fn produce(&mut self, record: &PublishedRecord, format: Format) {
+        let value = record.encode(format).unwrap();
+        self.topics.entry(record.topic.clone()).or_default().push(value);
+    }
+
+    fn consume(&self, topic: &str, format: Format) -> Candles {
+        let mut candles = Candles::default();
+        for value in &self.topics[topic] {
+            let record = feed_schema::decode(format, value).unwrap();
+            candles.apply(record);
+        }
+        candles
+    }
+}
+
+/// Runs the feed against the mock websocket until the last fixture trade is
+/// published, and returns every record it published
+async fn publish_fixtures() -> (Vec<PublishedRecord>, Vec<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let server = mock_coinbase(
+        listener,
+        vec![frames(FIRST_CONNECTION), frames(SECOND_CONNECTION)],
+    );
+
+    let (sink, mut rx) = ChannelSink::channel();
+    let mut handle = FeedPublisher::builder()
+        .markets(["BTC-USD", "ETH-USD"])
+        .channels([Channel::Ticker])
+        .ws_url(&url)
+        .backoff(Backoff::new(Duration::from_millis(10), Duration::from_millis(100)))
+        .shutdown_timeout(Duration::from_secs(1))
+        .sink(Box::new(sink))
+        .build()
+        .unwrap()
+        .start();
+
+    let mut records = Vec::new();
+    timeout(Duration::from_secs(10), async {
+        while let Some(record) = rx.recv().await {
+            let last = record.payload.contains(&format!("\"trade_id\":{}", LAST_TRADE_ID));
+            records.push(record);
+            if last {
+                break;
+            }
+        }
+    })
+    .await
+    .expect("the last fixture trade wasn't published");
+
+    handle.stop();
+    handle.join().await.unwrap();
+    while let Ok(record) = rx.try_recv() {
+        records.push(record);
+    }
+
+    let subscriptions = timeout(Duration::from_secs(5), server)
+        .await
+        .expect("the feed didn't close the websocket")
+        .unwrap();
+    (records, subscriptions)
+}
+
+fn volume(volume: &BigDecimal) -> BigDecimal {
+    volume.with_scale_round(8, RoundingMode::HalfUp)
+}
+
+fn decimal(value: &str) -> BigDecimal {
+    value.parse().unwrap()
+}
+
+fn minute(time: &str) -> DateTime<Utc> {
+    time.parse().unwrap()
+}
+
+fn assert_ohlc(candle: &Candle, open: f64, high: f64, low: f64, close: f64) {
+    assert_eq!(
+        (candle.open, candle.high, candle.low, candle.close),
+        (open, high, low, close),
+        "candle at {}",
+        candle.time
+    );
+}
+
+#[tokio::test]
+async fn tickers_become_candles() {
+    let (records, subscriptions) = publish_fixtures().await;
+
+    // subscribed on both connections
+    assert_eq!(subscriptions.len(), 2);
+    for subscribe in &subscriptions {
+        assert!(subscribe.contains("BTC-USD") && subscribe.contains("ETH-USD"), "{}", subscribe);
+        assert!(subscribe.contains("ticker"), "{}", subscribe);
+    }
+
+    // the trades sent again after the reconnect are published once
+    let tickers: Vec<&PublishedRecord> = records.iter().filter(|r| !r.marker).collect();
+    assert_eq!(tickers.len(), UNIQUE_TRADES);
+    assert!(tickers.iter().all(|r| r.topic == "coinbase-ticker"));
+
+    let mut broker = MemoryBroker::default();
+    for record in &records {
+        broker.produce(record, Format::Json);
+    }
+    let candles = broker.consume("coinbase-ticker", Format::Json);
+
+    let btc = candles.product("BTC-USD").unwrap();
+    assert_eq!(btc.len(), 2);
+    let first = &btc[&minute("2023-10-15T14:00:00Z")];
+    assert_ohlc(first, 27000.0, 27010.5, 26995.0, 27005.0);
+    assert_eq!((first.buy_count, first.sell_count), (3, 1));
+    assert_eq!(volume(&first.buy_volume), decimal("0.3"));
+    assert_eq!(volume(&first.sell_volume), decimal("0.2"));
+    assert_eq!(first.trades, vec![1, 2, 3, 4]);
+
+    let second = &btc[&minute("2023-10-15T14:01:00Z")];
+    assert_ohlc(second, 27020.0, 27020.0, 27015.0, 27015.0);
+    assert_eq!((second.buy_count, second.sell_count), (1, 1));
+    assert_eq!(volume(&second.buy_volume), decimal("0.1"));
+    assert_eq!(volume(&second.sell_volume), decimal("0.3"));
+
+    let eth = candles.product("ETH-USD").unwrap();
+    assert_eq!(eth.len(), 2);
+    let first = &eth[&minute("2023-10-15T14:00:00Z")];
+    assert_ohlc(first, 1550.0, 1552.25, 1550.0, 1552.25);
+    assert_eq!((first.buy_count, first.sell_count), (1, 1));
+    assert_eq!(volume(&first.buy_volume), decimal("1"));
+    assert_eq!(volume(&first.sell_volume), decimal("2"));
+
+    let second = &eth[&minute("2023-10-15T14:01:00Z")];
+    assert_ohlc(second, 1549.5, 1549.5, 1549.5, 1549.5);
+    assert_eq!((second.buy_count, second.sell_count), (0, 1));
+    assert_eq!(volume(&second.sell_volume), decimal("0.5"));
+}
+
+#[tokio::test]
+async fn every_format_gives_the_same_candles() {
+    let (records, _) = publish_fixtures().await;
+
+    let mut brokers: Vec<(Format, MemoryBroker)> =
+        [Format::Json, Format::MsgPack, Format::Protobuf, Format::Avro]
+            .into_iter()
+            .map(|format| (format, MemoryBroker::default()))
+            .collect();
+    for record in &records {
+        for (format, broker) in &mut brokers {
+            broker.produce(record, *format);
+        }
+    }
+
+    let summary = |candles: &Candles, product: &str| -> Vec<String> {
+        candles.product(product).unwrap().values().map(Candle::to_string).collect()
+    };
+    let (_, json) = &brokers[0];
+    let expected = json.consume("coinbase-ticker", Format::Json);
+    for (format, broker) in &brokers[1..] {
+        let candles = broker.consume("coinbase-ticker", *format);
+        for product in ["BTC-USD", "ETH-USD"] {
+            assert_eq!(
+                summary(&candles, product),
+                summary(&expected, product),
+                "{} candles of {}",
+                format,
+                product
+            );
+        }
+    }
+}
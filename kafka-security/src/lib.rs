@@ -0,0 +1,231 @@
+//! TLS and SASL for kafka connections, shared by the producer and its
+//! consumers.
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use rdkafka::ClientConfig;
+
+/// TLS settings for the kafka connection
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// CA certificate (PEM) the brokers are verified with, the system roots
+    /// are used when not set
+    pub ca_file: Option<PathBuf>,
+    /// client certificate (PEM) for mutual TLS
+    pub cert_file: Option<PathBuf>,
+    /// private key (PEM) of the client certificate
+    pub key_file: Option<PathBuf>,
+    pub verify_hostname: bool,
+}
+
+/// SASL credentials for the kafka connection
+#[derive(Clone)]
+pub struct SaslConfig {
+    /// 'PLAIN', 'SCRAM-SHA-256' or 'SCRAM-SHA-512'
+    pub mechanism: String,
+    pub username: String,
+    pub password: String,
+}
+
+// keep the password out of logs
+impl std::fmt::Debug for SaslConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SaslConfig")
+            .field("mechanism", &self.mechanism)
+            .field("username", &self.username)
+            .finish()
+    }
+}
+
+/// How to connect to kafka, plaintext when neither is set
+#[derive(Debug, Clone, Default)]
+pub struct SecurityConfig {
+    pub tls: Option<TlsConfig>,
+    pub sasl: Option<SaslConfig>,
+}
+
+const MECHANISMS: [&str; 3] = ["PLAIN", "SCRAM-SHA-256", "SCRAM-SHA-512"];
+
+impl SecurityConfig {
+    /// TLS from KAFKA_TLS=true or any of KAFKA_CA_FILE, KAFKA_CERT_FILE and
+    /// KAFKA_KEY_FILE, KAFKA_TLS_VERIFY_HOSTNAME=false skips the host name
+    /// check. SASL from KAFKA_SASL_MECHANISM, KAFKA_SASL_USERNAME and
+    /// KAFKA_SASL_PASSWORD.
+    pub fn from_env() -> Result<Self, String> {
+        let path = |name: &str| env::var_os(name).map(PathBuf::from);
+        let var = |name: &str| env::var(name).ok();
+
+        let ca_file = path("KAFKA_CA_FILE");
+        let cert_file = path("KAFKA_CERT_FILE");
+        let key_file = path("KAFKA_KEY_FILE");
+        let enabled = var("KAFKA_TLS").map(|v| v == "true").unwrap_or(false);
+        let tls = if enabled || ca_file.is_some() || cert_file.is_some() || key_file.is_some() {
+            let verify_hostname = var("KAFKA_TLS_VERIFY_HOSTNAME")
+                .map(|v| v != "false")
+                .unwrap_or(true);
+            Some(TlsConfig {
+                ca_file,
+                cert_file,
+                key_file,
+                verify_hostname,
+            })
+        } else {
+            None
+        };
+
+        let sasl = SaslConfig::new(
+            var("KAFKA_SASL_MECHANISM"),
+            var("KAFKA_SASL_USERNAME"),
+            var("KAFKA_SASL_PASSWORD"),
+        )?;
+
+        let security = Self { tls, sasl };
+        security.check()?;
+        Ok(security)
+    }
+
+    /// Checks that the certificate files can be read, so a typo is reported
+    /// before the client connects
+    pub fn check(&self) -> Result<(), String> {
+        let tls = match &self.tls {
+            Some(tls) => tls,
+            None => return Ok(()),
+        };
+
+        match (&tls.cert_file, &tls.key_file) {
+            (Some(_), None) => return Err("a client certificate needs its key".into()),
+            (None, Some(_)) => return Err("a client key needs its certificate".into()),
+            _ => {}
+        }
+
+        for file in [&tls.ca_file, &tls.cert_file, &tls.key_file].into_iter().flatten() {
+            readable(file)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the security options of a librdkafka client
+    pub fn apply(&self, config: &mut ClientConfig) {
+        let protocol = match (&self.tls, &self.sasl) {
+            (None, None) => "plaintext",
+            (Some(_), None) => "ssl",
+            (None, Some(_)) => "sasl_plaintext",
+            (Some(_), Some(_)) => "sasl_ssl",
+        };
+        config.set("security.protocol", protocol);
+
+        if let Some(tls) = &self.tls {
+            let path = |file: &Path| file.to_string_lossy().into_owned();
+            if let Some(ca_file) = &tls.ca_file {
+                config.set("ssl.ca.location", path(ca_file));
+            }
+            if let (Some(cert_file), Some(key_file)) = (&tls.cert_file, &tls.key_file) {
+                config.set("ssl.certificate.location", path(cert_file));
+                config.set("ssl.key.location", path(key_file));
+            }
+            let identification = if tls.verify_hostname { "https" } else { "none" };
+            config.set("ssl.endpoint.identification.algorithm", identification);
+        }
+
+        if let Some(sasl) = &self.sasl {
+            config
+                .set("sasl.mechanism", &sasl.mechanism)
+                .set("sasl.username", &sasl.username)
+                .set("sasl.password", &sasl.password);
+        }
+    }
+}
+
+impl SaslConfig {
+    /// SASL when any of the settings is given, all of them are needed then
+    pub fn new(
+        mechanism: Option<String>,
+        username: Option<String>,
+        password: Option<String>,
+    ) -> Result<Option<Self>, String> {
+        let (mechanism, username, password) = match (mechanism, username, password) {
+            (None, None, None) => return Ok(None),
+            (Some(mechanism), Some(username), Some(password)) => (mechanism, username, password),
+            _ => return Err("SASL needs a mechanism, a user name and a password".into()),
+        };
+
+        let mechanism = mechanism.to_ascii_uppercase();
+        if !MECHANISMS.contains(&mechanism.as_str()) {
+            return Err(format!(
+                "unknown SASL mechanism '{}', expected {}",
+                mechanism,
+                MECHANISMS.join(", ")
+            ));
+        }
+
+        Ok(Some(Self {
+            mechanism,
+            username,
+            password,
+        }))
+    }
+}
+
+fn readable(file: &Path) -> Result<(), String> {
+    std::fs::File::open(file)
+        .map(|_| ())
+        .map_err(|e| format!("failed reading {}: {}", file.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tls() -> TlsConfig {
+        TlsConfig {
+            ca_file: None,
+            cert_file: None,
+            key_file: None,
+            verify_hostname: true,
+        }
+    }
+
+    #[test]
+    fn reports_unreadable_certificates() {
+        let security = SecurityConfig {
+            tls: Some(TlsConfig {
+                ca_file: Some(PathBuf::from("/nonexistent/ca.pem")),
+                ..tls()
+            }),
+            sasl: None,
+        };
+        let e = security.check().err().unwrap();
+        assert!(e.contains("/nonexistent/ca.pem"), "{}", e);
+    }
+
+    #[test]
+    fn needs_every_sasl_setting() {
+        let some = |value: &str| Some(value.to_string());
+        assert!(SaslConfig::new(None, None, None).unwrap().is_none());
+        assert!(SaslConfig::new(some("SCRAM-SHA-512"), some("feed"), None).is_err());
+        assert!(SaslConfig::new(some("GSSAPI"), some("feed"), some("secret")).is_err());
+
+        let sasl = SaslConfig::new(some("scram-sha-512"), some("feed"), some("secret"));
+        assert_eq!(sasl.unwrap().unwrap().mechanism, "SCRAM-SHA-512");
+    }
+
+    #[test]
+    fn sets_the_protocol() {
+        let sasl = SaslConfig {
+            mechanism: "SCRAM-SHA-512".to_string(),
+            username: "feed".to_string(),
+            password: "secret".to_string(),
+        };
+        let protocol = |tls: Option<TlsConfig>, sasl: Option<SaslConfig>| {
+            let mut config = ClientConfig::new();
+            SecurityConfig { tls, sasl }.apply(&mut config);
+            config.get("security.protocol").unwrap().to_string()
+        };
+
+        assert_eq!(protocol(None, None), "plaintext");
+        assert_eq!(protocol(Some(tls()), None), "ssl");
+        assert_eq!(protocol(None, Some(sasl.clone())), "sasl_plaintext");
+        assert_eq!(protocol(Some(tls()), Some(sasl)), "sasl_ssl");
+    }
+}